@@ -13,6 +13,6 @@
 // limitations under the License.
 
 use criterion::criterion_main;
-use weak_heap::benches::large;
+use weak_heap::benches::concurrent;
 
-criterion_main!(large::i128_sort_binary);
+criterion_main!(concurrent::scaling);
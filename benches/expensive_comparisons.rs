@@ -13,6 +13,6 @@
 // limitations under the License.
 
 use criterion::criterion_main;
-use weak_heap::benches::tiny;
+use weak_heap::benches::expensive;
 
-criterion_main!(tiny::i128_sort_weak);
+criterion_main!(expensive::sweep);
@@ -12,7 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use criterion::criterion_main;
-use weak_heap::benches::large;
+//! Runs the benchmarks selected by the environment. See
+//! `weak_heap::benches::suite` for the variables that are read.
 
-criterion_main!(large::i32_sort_binary);
+use weak_heap::benches::suite::{self, Selection};
+
+fn main() {
+  suite::run(&Selection::from_env());
+}
@@ -12,7 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use criterion::criterion_main;
-use weak_heap::benches::large;
+#![no_main]
 
-criterion_main!(large::i128_sort_weak);
+use libfuzzer_sys::fuzz_target;
+use weak_heap::script::Script;
+
+fuzz_target!(|script: Script| {
+  script.replay();
+});
@@ -0,0 +1,487 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A `WeakHeap` whose elements can be awaited, independent of any executor.
+//!
+//! `AsyncWeakHeap::pop` returns a future that completes with the greatest
+//! element once there is one. Pushing never blocks, so it may be done from
+//! synchronous or asynchronous code, and wakes one waiting future through its
+//! `Waker`. With the `futures-core` feature, `AsyncWeakHeap::stream` implements
+//! `Stream`.
+//!
+//! ```rust
+//! # use std::future::Future;
+//! # use std::pin::pin;
+//! # use std::sync::Arc;
+//! # use std::task::{Context, Poll, Wake, Waker};
+//! # use weak_heap::async_queue::AsyncWeakHeap;
+//! # struct Noop;
+//! # impl Wake for Noop { fn wake(self: Arc<Self>) {} }
+//! # fn main() {
+//! let queue = AsyncWeakHeap::new();
+//! let waker = Waker::from(Arc::new(Noop));
+//! let mut cx = Context::from_waker(&waker);
+//! let mut pop = pin!(queue.pop());
+//! assert_eq!(pop.as_mut().poll(&mut cx), Poll::Pending);
+//! queue.push(3).unwrap();
+//! queue.push(5).unwrap();
+//! assert_eq!(pop.as_mut().poll(&mut cx), Poll::Ready(Some(5)));
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+#[cfg(feature = "futures-core")]
+use futures_core::Stream;
+use crate::WeakHeap;
+use crate::sync::PushError;
+
+#[derive(Debug)]
+struct State<T: Debug + Ord> {
+  heap: WeakHeap<T>,
+  closed: bool,
+  /// The wakers of pending pops, oldest first, with the ids of the pops.
+  waiters: VecDeque<(u64, Waker)>,
+  next_id: u64,
+}
+
+/// A max-heap shared between tasks, whose pops are futures.
+///
+/// Once the queue is closed, pushes fail, and pops complete with the remaining
+/// elements and then with `None`.
+///
+/// As with `SyncWeakHeap`, a panic from an `Ord` implementation while the heap
+/// is being modified poisons the queue. The heap may be left out of order, so
+/// as the panic unwinds its elements are dropped, it is closed and every
+/// pending pop is woken.
+#[derive(Debug)]
+pub struct AsyncWeakHeap<T: Debug + Ord> {
+  state: Mutex<State<T>>,
+}
+
+impl<T: Debug + Ord> Default for AsyncWeakHeap<T> {
+  fn default() -> Self {
+    AsyncWeakHeap::new()
+  }
+}
+
+impl<T: Debug + Ord> AsyncWeakHeap<T> {
+  /// Creates a new, empty queue.
+  pub fn new() -> Self {
+    AsyncWeakHeap {
+      state: Mutex::new(State { heap: WeakHeap::new(), closed: false, waiters: VecDeque::new(), next_id: 0, }),
+    }
+  }
+
+  /// Locks the queue's state. A panic while it was locked can only come from
+  /// `with_heap`, which has already closed and emptied the queue, so the state
+  /// is consistent even if the lock is poisoned.
+  fn lock(&self) -> MutexGuard<'_, State<T>> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// Returns `true` iff a panic while the queue was locked has poisoned it. A
+  /// poisoned queue is closed and empty.
+  pub fn is_poisoned(&self) -> bool {
+    self.state.is_poisoned()
+  }
+
+  /// Returns the number of elements in the queue.
+  pub fn len(&self) -> usize {
+    self.lock().heap.len()
+  }
+
+  /// Returns `true` iff the queue is empty.
+  pub fn is_empty(&self) -> bool {
+    self.lock().heap.is_empty()
+  }
+
+  /// Returns `true` iff the queue has been closed.
+  pub fn is_closed(&self) -> bool {
+    self.lock().closed
+  }
+
+  /// Pushes `value` onto the queue, waking the longest-waiting pop. Fails if the
+  /// queue is closed.
+  pub fn push(&self, value: T) -> Result<(), PushError<T>> {
+    let state = self.lock();
+    if state.closed {
+      return Err(PushError(value));
+    }
+    let (mut state, ()) = with_heap(state, |heap| heap.push(value));
+    let waiter = state.waiters.pop_front();
+    drop(state);
+    if let Some((_, waker)) = waiter {
+      waker.wake();
+    }
+    Ok(())
+  }
+
+  /// Removes the greatest element from the queue and returns it, if there is
+  /// one, without waiting.
+  pub fn try_pop(&self) -> Option<T> {
+    with_heap(self.lock(), WeakHeap::pop).1
+  }
+
+  /// Returns a future that removes the greatest element from the queue once
+  /// there is one, or completes with `None` once the queue is empty and closed.
+  pub fn pop(&self) -> Pop<'_, T> {
+    Pop { waiter: Waiter { queue: self, id: None } }
+  }
+
+  /// Returns a stream of the elements popped from the queue, which ends once
+  /// the queue is empty and closed.
+  pub fn stream(&self) -> PopStream<'_, T> {
+    PopStream { waiter: Waiter { queue: self, id: None } }
+  }
+
+  /// Closes the queue, waking every pending pop. Closing a closed queue does
+  /// nothing.
+  pub fn close(&self) {
+    let mut state = self.lock();
+    state.closed = true;
+    let waiters = std::mem::take(&mut state.waiters);
+    drop(state);
+    for (_, waker) in waiters {
+      waker.wake();
+    }
+  }
+}
+
+/// Runs `f`, which may call into `Ord`, on the heap of the locked `state`, and
+/// returns the lock with the result. If `f` panics, the queue is closed and
+/// emptied, and every pending pop is woken once the lock is released.
+fn with_heap<'a, T, R, F>(state: MutexGuard<'a, State<T>>, f: F) -> (MutexGuard<'a, State<T>>, R)
+where T: Debug + Ord, F: FnOnce(&mut WeakHeap<T>) -> R {
+  let mut guard = PoisonGuard(Some(state));
+  let result = f(&mut guard.0.as_mut().unwrap().heap);
+  (guard.0.take().unwrap(), result)
+}
+
+/// Poisons the queue if dropped still holding its lock. See `with_heap`.
+struct PoisonGuard<'a, T: Debug + Ord>(Option<MutexGuard<'a, State<T>>>);
+
+impl<'a, T: Debug + Ord> Drop for PoisonGuard<'a, T> {
+  fn drop(&mut self) {
+    if let Some(mut state) = self.0.take() {
+      state.closed = true;
+      state.heap = WeakHeap::new();
+      let waiters = std::mem::take(&mut state.waiters);
+      drop(state);
+      for (_, waker) in waiters {
+        waker.wake();
+      }
+    }
+  }
+}
+
+/// A pop's registration with its queue, shared by `Pop` and `PopStream`.
+#[derive(Debug)]
+struct Waiter<'a, T: Debug + Ord> {
+  queue: &'a AsyncWeakHeap<T>,
+  /// The id of the last waker registered, if the pop is pending or was woken
+  /// but has not yet been polled again.
+  id: Option<u64>,
+}
+
+impl<'a, T: Debug + Ord> Waiter<'a, T> {
+  fn poll_pop(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+    let (mut state, value) = with_heap(self.queue.lock(), WeakHeap::pop);
+    if let Some(value) = value {
+      self.deregister(&mut state);
+      return Poll::Ready(Some(value));
+    }
+    if state.closed {
+      self.deregister(&mut state);
+      return Poll::Ready(None);
+    }
+    let registered = self.id.and_then(|id| state.waiters.iter_mut().find(|(i, _)| *i == id));
+    match registered {
+      Some((_, waker)) => {
+        if !waker.will_wake(cx.waker()) {
+          *waker = cx.waker().clone();
+        }
+      },
+      None => {
+        let id = state.next_id;
+        state.next_id += 1;
+        state.waiters.push_back((id, cx.waker().clone()));
+        self.id = Some(id);
+      },
+    }
+    Poll::Pending
+  }
+
+  /// Withdraws the registration of a pop that no longer waits. If it had been
+  /// woken by a push whose element it did not take, the wakeup passes to the
+  /// next waiter so that the element is not stranded.
+  fn deregister(&mut self, state: &mut State<T>) -> Option<Waker> {
+    let id = self.id.take()?;
+    match state.waiters.iter().position(|(i, _)| *i == id) {
+      Some(index) => {
+        state.waiters.remove(index);
+        None
+      },
+      None if !state.heap.is_empty() => state.waiters.pop_front().map(|(_, waker)| waker),
+      None => None,
+    }
+  }
+}
+
+impl<'a, T: Debug + Ord> Drop for Waiter<'a, T> {
+  fn drop(&mut self) {
+    if self.id.is_some() {
+      let waker = self.deregister(&mut self.queue.lock());
+      if let Some(waker) = waker {
+        waker.wake();
+      }
+    }
+  }
+}
+
+/// A future that pops the greatest element from an `AsyncWeakHeap`, returned
+/// by `AsyncWeakHeap::pop`.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Pop<'a, T: Debug + Ord> {
+  waiter: Waiter<'a, T>,
+}
+
+impl<'a, T: Debug + Ord> Future for Pop<'a, T> {
+  type Output = Option<T>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+    self.waiter.poll_pop(cx)
+  }
+}
+
+/// A stream of the elements popped from an `AsyncWeakHeap`, returned by
+/// `AsyncWeakHeap::stream`.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct PopStream<'a, T: Debug + Ord> {
+  waiter: Waiter<'a, T>,
+}
+
+impl<'a, T: Debug + Ord> PopStream<'a, T> {
+  /// Polls for the next element, as `Stream::poll_next` does.
+  pub fn poll_next_element(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+    self.waiter.poll_pop(cx)
+  }
+}
+
+#[cfg(feature = "futures-core")]
+impl<'a, T: Debug + Ord> Stream for PopStream<'a, T> {
+  type Item = T;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+    self.waiter.poll_pop(cx)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::future::{self, Future};
+  use std::pin::pin;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+  use std::task::{Context, Poll, Wake, Waker};
+  use std::thread::{self, Thread};
+  use std::time::Duration;
+  use super::AsyncWeakHeap;
+
+  /// Wakes a thread blocked in `block_on`.
+  struct Unpark(Thread);
+
+  impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+      self.0.unpark();
+    }
+  }
+
+  /// A minimal executor that runs `future` on the current thread.
+  fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+      match future.as_mut().poll(&mut cx) {
+        Poll::Ready(output) => return output,
+        Poll::Pending => thread::park(),
+      }
+    }
+  }
+
+  /// Counts how often it is woken.
+  #[derive(Default)]
+  struct Count(AtomicUsize);
+
+  impl Wake for Count {
+    fn wake(self: Arc<Self>) {
+      self.0.fetch_add(1, Ordering::SeqCst);
+    }
+  }
+
+  fn counting_waker() -> (Arc<Count>, Waker) {
+    let count = Arc::new(Count::default());
+    (Arc::clone(&count), Waker::from(count))
+  }
+
+  #[test]
+  fn pop_waits_for_push_from_another_thread() {
+    let queue = Arc::new(AsyncWeakHeap::new());
+    let pusher = {
+      let queue = Arc::clone(&queue);
+      thread::spawn(move || {
+        for x in 0..100 {
+          if x % 10 == 0 {
+            thread::sleep(Duration::from_millis(1));
+          }
+          queue.push(x).unwrap();
+        }
+        queue.close();
+      })
+    };
+    let mut popped = block_on(async {
+      let mut popped = Vec::new();
+      while let Some(x) = queue.pop().await {
+        popped.push(x);
+      }
+      popped
+    });
+    pusher.join().unwrap();
+    popped.sort_unstable();
+    assert_eq!(popped, (0..100).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn push_wakes_one_waiter_in_order() {
+    let queue = AsyncWeakHeap::new();
+    let (first_count, first_waker) = counting_waker();
+    let (second_count, second_waker) = counting_waker();
+    let mut first = pin!(queue.pop());
+    let mut second = pin!(queue.pop());
+    assert!(first.as_mut().poll(&mut Context::from_waker(&first_waker)).is_pending());
+    assert!(second.as_mut().poll(&mut Context::from_waker(&second_waker)).is_pending());
+
+    queue.push(1).unwrap();
+    assert_eq!(first_count.0.load(Ordering::SeqCst), 1);
+    assert_eq!(second_count.0.load(Ordering::SeqCst), 0);
+    assert_eq!(first.as_mut().poll(&mut Context::from_waker(&first_waker)), Poll::Ready(Some(1)));
+
+    queue.close();
+    assert_eq!(second_count.0.load(Ordering::SeqCst), 1);
+    assert_eq!(second.as_mut().poll(&mut Context::from_waker(&second_waker)), Poll::Ready(None));
+    assert!(queue.push(2).is_err());
+  }
+
+  #[test]
+  fn dropped_waiter_passes_on_its_wakeup() {
+    let queue = AsyncWeakHeap::new();
+    let (_, first_waker) = counting_waker();
+    let (second_count, second_waker) = counting_waker();
+    let mut second = Box::pin(queue.pop());
+    {
+      let mut first = Box::pin(queue.pop());
+      assert!(first.as_mut().poll(&mut Context::from_waker(&first_waker)).is_pending());
+      assert!(second.as_mut().poll(&mut Context::from_waker(&second_waker)).is_pending());
+      queue.push(7).unwrap();
+    }
+    assert_eq!(second_count.0.load(Ordering::SeqCst), 1);
+    assert_eq!(second.as_mut().poll(&mut Context::from_waker(&second_waker)), Poll::Ready(Some(7)));
+  }
+
+  /// An element whose comparisons panic once `EXPLODE` is set.
+  #[derive(Debug, Eq, PartialEq)]
+  struct Fragile(i32);
+
+  static EXPLODE: AtomicBool = AtomicBool::new(false);
+
+  impl PartialOrd for Fragile {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+      Some(self.cmp(other))
+    }
+  }
+
+  impl Ord for Fragile {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+      assert!(!EXPLODE.load(Ordering::SeqCst), "comparison exploded");
+      self.0.cmp(&other.0)
+    }
+  }
+
+  #[test]
+  fn panic_in_ord_closes_the_queue_and_wakes_pending_pops() {
+    let queue = Arc::new(AsyncWeakHeap::new());
+    let (first_count, first_waker) = counting_waker();
+    let (second_count, second_waker) = counting_waker();
+    let mut first = pin!(queue.pop());
+    let mut second = pin!(queue.pop());
+    assert!(first.as_mut().poll(&mut Context::from_waker(&first_waker)).is_pending());
+    assert!(second.as_mut().poll(&mut Context::from_waker(&second_waker)).is_pending());
+    // Wakes the first pop, which has yet to take the element.
+    queue.push(Fragile(1)).unwrap();
+    EXPLODE.store(true, Ordering::SeqCst);
+    let pushed = thread::spawn({
+      let queue = Arc::clone(&queue);
+      move || queue.push(Fragile(2))
+    }).join();
+    EXPLODE.store(false, Ordering::SeqCst);
+    assert!(pushed.is_err());
+    assert_eq!(first_count.0.load(Ordering::SeqCst), 1);
+    assert_eq!(second_count.0.load(Ordering::SeqCst), 1);
+    assert!(queue.is_poisoned());
+    assert_eq!(first.as_mut().poll(&mut Context::from_waker(&first_waker)), Poll::Ready(None));
+    assert_eq!(second.as_mut().poll(&mut Context::from_waker(&second_waker)), Poll::Ready(None));
+    assert!(queue.push(Fragile(3)).is_err());
+    assert_eq!(queue.try_pop(), None);
+  }
+
+  #[test]
+  fn stream_yields_in_priority_order() {
+    let queue = AsyncWeakHeap::new();
+    for x in &[2, 7, 1, 8, 2, 8] {
+      queue.push(*x).unwrap();
+    }
+    queue.close();
+    let mut stream = queue.stream();
+    let popped = block_on(async {
+      let mut popped = Vec::new();
+      while let Some(x) = future::poll_fn(|cx| stream.poll_next_element(cx)).await {
+        popped.push(x);
+      }
+      popped
+    });
+    assert_eq!(popped, vec![8, 8, 7, 2, 2, 1]);
+  }
+
+  #[cfg(feature = "futures-core")]
+  #[test]
+  fn implements_stream() {
+    use futures_core::Stream;
+    let queue = AsyncWeakHeap::new();
+    queue.push(1).unwrap();
+    queue.push(3).unwrap();
+    queue.close();
+    let mut stream = pin!(queue.stream());
+    let mut next = || block_on(future::poll_fn(|cx| stream.as_mut().poll_next(cx)));
+    assert_eq!(next(), Some(3));
+    assert_eq!(next(), Some(1));
+    assert_eq!(next(), None);
+  }
+}
@@ -12,114 +12,39 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use criterion::{BatchSize, Bencher, Criterion};
 use num_bigint::{BigUint, ToBigUint};
 use rand::{Rng, SeedableRng};
 use rand::distributions::Standard;
 use rand::rngs::StdRng;
-use rand::distributions::Distribution;
-use std::cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd};
-use std::mem;
-use std::ops::Add;
-use std::sync::atomic;
-
-#[derive(Clone, Copy, Debug)]
-pub struct ComparisonCountedI32(i32);
-
-static PARTIAL_ORD_COMPARISON_COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
-impl PartialOrd for ComparisonCountedI32 {
-  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-    PARTIAL_ORD_COMPARISON_COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
-    self.0.partial_cmp(&other.0)
-  }
-}
-
-static ORD_COMPARISON_COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
-impl Ord for ComparisonCountedI32 {
-  fn cmp(&self, other: &Self) -> Ordering {
-    ORD_COMPARISON_COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
-    self.0.cmp(&other.0)
-  }
-}
-
-static PARTIAL_EQ_COMPARISON_COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
-static PARTIAL_NEQ_COMPARISON_COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
-impl PartialEq for ComparisonCountedI32 {
-  fn eq(&self, other: &Self) -> bool {
-    PARTIAL_EQ_COMPARISON_COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
-    self.0.eq(&other.0)
-  }
-
-  fn ne(&self, other: &Self) -> bool {
-    PARTIAL_NEQ_COMPARISON_COUNTER.fetch_add(1, atomic::Ordering::SeqCst);
-    self.0.ne(&other.0)
-  }
-}
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use std::any;
+use std::collections::HashMap;
+use std::fmt;
+use crate::{PriorityQueue, StdBinaryHeap, WeakHeap};
 
-impl Eq for ComparisonCountedI32 {}
+pub use crate::counted::{ComparisonCounts, Counted};
 
-impl From<i32> for ComparisonCountedI32 {
-  fn from(x: i32) -> Self {
-    ComparisonCountedI32(x)
-  }
-}
-
-pub struct StandardComparisonCountedI32Distribution {}
-
-impl Distribution<ComparisonCountedI32> for StandardComparisonCountedI32Distribution {
-  fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> ComparisonCountedI32 {
-    return rng.sample::<i32, rand::distributions::Standard>(rand::distributions::Standard).into()
-  }
-}
-
-#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, PartialOrd, Ord)]
-pub struct ComparisonCounts {
-  pub partial_ord: usize,
-  pub ord: usize,
-  pub eq: usize,
-  pub neq: usize,
-}
-
-impl ComparisonCounts {
-  pub fn now() -> Self {
-    ComparisonCounts {
-      partial_ord: PARTIAL_ORD_COMPARISON_COUNTER.load(atomic::Ordering::SeqCst),
-      ord: ORD_COMPARISON_COUNTER.load(atomic::Ordering::SeqCst),
-      eq: PARTIAL_EQ_COMPARISON_COUNTER.load(atomic::Ordering::SeqCst),
-      neq: PARTIAL_NEQ_COMPARISON_COUNTER.load(atomic::Ordering::SeqCst),
-    }
-  }
+pub trait HasDistribution: Sized {
+  type Dist: rand::distributions::Distribution<Self>;
 
-  pub fn take_difference(&mut self) {
-    let mut now = ComparisonCounts::now();
-    assert!(now.partial_ord >= self.partial_ord);
-    now.partial_ord -= self.partial_ord;
-    assert!(now.ord >= self.ord);
-    now.ord -= self.ord;
-    assert!(now.eq >= self.eq);
-    now.eq -= self.eq;
-    assert!(now.neq >= self.neq);
-    now.neq -= self.neq;
-    mem::swap(&mut now, self);
-  }
+  fn distribution() -> Self::Dist;
 }
 
-impl Add for ComparisonCounts {
-  type Output = Self;
+/// Samples `Counted` values whose wrapped values are drawn from `D`.
+pub struct CountedDistribution<D>(D);
 
-  fn add(self, rhs: Self) -> Self {
-    ComparisonCounts {
-      partial_ord: self.partial_ord + rhs.partial_ord,
-      ord: self.ord + rhs.ord,
-      eq: self.eq + rhs.eq,
-      neq: self.neq + rhs.neq,
-    }
+impl<T, D: Distribution<T>> Distribution<Counted<T>> for CountedDistribution<D> {
+  fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Counted<T> {
+    Counted(self.0.sample(rng))
   }
 }
 
-pub trait HasDistribution: Sized {
-  type Dist: rand::distributions::Distribution<Self>;
+impl<T: HasDistribution> HasDistribution for Counted<T> {
+  type Dist = CountedDistribution<T::Dist>;
 
-  fn distribution() -> Self::Dist;
+  fn distribution() -> Self::Dist { CountedDistribution(T::distribution()) }
 }
 
 impl HasDistribution for i32 {
@@ -161,113 +86,288 @@ impl HasDistribution for U32768 {
 /// Returns a sequence of `size` elements generated deterministically from an
 /// RNG with a fixed seed.
 pub fn get_values<T: HasDistribution>(size: usize) -> Vec<T> {
-  let seed: [u8; 32] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31];
-  let mut rng: StdRng = SeedableRng::from_seed(seed);
+  let mut rng = seeded_rng();
   rng.sample_iter(&T::distribution()).take(size).collect()
 }
 
-// /// Runs benchmarks over sequences of size `$sizes`. For example:
-// ///
-// /// ```rust,noexec
-// /// fn bench_something(c: &mut Criterion) {
-// ///   do_comparison_counted_i32_bench!(c, WeakHeap::new, 1..127);
-// /// }
-// /// ```
-// ///
-// /// Benchmarks will be run using a customized version of `i32` that counts the
-// /// number of comparison operations performed.
-// #[macro_export]
-// macro_rules! do_comparison_counted_i32_bench {
-//   ($bencher: ident, $heap_factory: expr, $sizes: expr) => ({
-//     use $crate::benches::{ComparisonCountedI32/*, ComparisonCounts*/};
-//     use std::collections::HashMap;
-//     let mut all_values = HashMap::new();
-//     let mut all_sorted = HashMap::new();
-//     for size in $sizes {
-//       let values: Vec<ComparisonCountedI32> =
-//         $crate::benches::get_values::<i32>(size).into_iter().map(|x| x.into()).collect();
-//       let sorted = {
-//         let mut v = values.clone();
-//         v.sort_by(|x, y| y.cmp(x));
-//         v
-//       };
-//       all_values.insert(size, values);
-//       all_sorted.insert(size, sorted);
-//     }
-
-//     $bencher.bench_function_over_inputs(
-//       &format!("sort (counting comparisons) {}", stringify!($heap_factory)),
-//       move |b: &mut criterion::Bencher, size: &usize| {
-//         // let mut all_counts = Vec::new();
-//         // let mut iterations = 0;
-//         let values = all_values.get(size).unwrap();
-//         let sorted = all_sorted.get(size).unwrap();
-//         b.iter(|| {
-//           // iterations += 1;
-//           // let mut counts = ComparisonCounts::now();
-//           let mut heap = $heap_factory();
-//           for v in values {
-//             heap.push(*v);
-//           }
-//           let mut heap_sorted = Vec::with_capacity(heap.len());
-//           while let Some(x) = heap.pop() {
-//             heap_sorted.push(x);
-//           }
-//           // counts.take_difference();
-//           // all_counts.push(counts);
-//           assert_eq!(heap_sorted, *sorted);
-//         });
-//         // let total_counts = all_counts.into_iter().fold(
-//         //   ComparisonCounts::default(), |acc, x| acc + x);
-//         // println!("\ndo_comparison_counted_bench({}, {}) partial_ord {:.2}, ord {:.2}, eq {:.2}, ne {:.2}",
-//         //          stringify!($heap_factory), size,
-//         //          (total_counts.partial_ord as f32) / ((size * iterations) as f32),
-//         //          (total_counts.ord as f32) / ((size * iterations) as f32),
-//         //          (total_counts.eq as f32) / ((size * iterations) as f32),
-//         //          (total_counts.neq as f32) / ((size * iterations) as f32));
-//       }, $sizes);
-//   })
-// }
-
-/// Runs benchmarks over sequences of size `$sizes`.
-#[macro_export]
-macro_rules! do_bench {
-  ($bencher: ident, $type: ty, $heap_factory: expr, $sizes: expr) => ({
-    use std::collections::HashMap;
-    let mut all_values = HashMap::new();
-    let mut all_sorted = HashMap::new();
-    for size in $sizes {
-      let values: Vec<$type> = $crate::benches::get_values(size);
-      let sorted = {
-        let mut v = values.clone();
-        v.sort_by(|x, y| y.cmp(x));
-        v
-      };
-      all_values.insert(size, values);
-      all_sorted.insert(size, sorted);
+pub(crate) fn seeded_rng() -> StdRng {
+  let seed: [u8; 32] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31];
+  SeedableRng::from_seed(seed)
+}
+
+/// The arrangement of the values in a benchmark's input sequence.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Pattern {
+  /// Values drawn independently from `HasDistribution::distribution`, as
+  /// returned by `get_values`.
+  Uniform,
+  /// Uniform values in ascending order.
+  Sorted,
+  /// Uniform values in descending order.
+  ReverseSorted,
+  /// Sorted values in which about one percent of elements have been swapped
+  /// with another element at random.
+  NearlySorted,
+  /// Uniform values that ascend to a peak in the middle and then descend.
+  OrganPipe,
+  /// Values drawn from a pool of eight distinct uniform values.
+  FewUnique,
+  /// Values drawn from a pool of `size` distinct uniform values, where the
+  /// `k`th largest value is drawn with probability proportional to `1 / k`.
+  Zipf,
+}
+
+impl Pattern {
+  /// Every pattern, in the order in which benchmarks run them.
+  pub const ALL: [Pattern; 7] = [
+    Pattern::Uniform, Pattern::Sorted, Pattern::ReverseSorted, Pattern::NearlySorted,
+    Pattern::OrganPipe, Pattern::FewUnique, Pattern::Zipf,
+  ];
+
+  /// Returns the name of this pattern as it appears in benchmark ids.
+  pub fn name(self) -> &'static str {
+    match self {
+      Pattern::Uniform => "uniform",
+      Pattern::Sorted => "sorted",
+      Pattern::ReverseSorted => "reverse-sorted",
+      Pattern::NearlySorted => "nearly-sorted",
+      Pattern::OrganPipe => "organ-pipe",
+      Pattern::FewUnique => "few-unique",
+      Pattern::Zipf => "zipf",
     }
+  }
+}
+
+/// Returns a sequence of `size` elements arranged according to `pattern`,
+/// generated deterministically from an RNG with a fixed seed.
+pub fn get_pattern_values<T: HasDistribution + Clone + Ord>(pattern: Pattern, size: usize) -> Vec<T> {
+  let mut values = get_values::<T>(size);
+  let mut rng = seeded_rng();
+  match pattern {
+    Pattern::Uniform => (),
+    Pattern::Sorted => values.sort(),
+    Pattern::ReverseSorted => values.sort_by(|x, y| y.cmp(x)),
+    Pattern::NearlySorted => {
+      values.sort();
+      if size > 1 {
+        for _ in 0..(size / 100).max(1) {
+          values.swap(rng.gen_range(0, size), rng.gen_range(0, size));
+        }
+      }
+    },
+    Pattern::OrganPipe => {
+      values.sort();
+      let descending: Vec<T> = values.iter().skip(1).step_by(2).rev().cloned().collect();
+      values = values.into_iter().step_by(2).chain(descending).collect();
+    },
+    Pattern::FewUnique => {
+      let pool = get_values::<T>(8);
+      values = (0..size).map(|_| pool.choose(&mut rng).unwrap().clone()).collect();
+    },
+    Pattern::Zipf => {
+      if size > 0 {
+        let mut pool = values;
+        pool.sort_by(|x, y| y.cmp(x));
+        let ranks = WeightedIndex::new((1..=size).map(|k| 1.0 / k as f64)).unwrap();
+        values = (0..size).map(|_| pool[ranks.sample(&mut rng)].clone()).collect();
+      }
+    },
+  }
+  values
+}
+
+/// Prints the number of comparisons of each kind that the benchmark `id` made
+/// per element, given that it made `counts` comparisons over `elements`
+/// elements.
+pub fn report_comparisons(id: &str, elements: usize, counts: ComparisonCounts) {
+  let n = elements.max(1) as f64;
+  println!("{}: comparisons per element: partial_ord {:.2}, ord {:.2}, eq {:.2}, ne {:.2}",
+           id,
+           counts.partial_ord as f64 / n,
+           counts.ord as f64 / n,
+           counts.eq as f64 / n,
+           counts.neq as f64 / n);
+}
+
+/// A family of priority queues, with one queue type for each element type.
+/// This lets a benchmark build both a queue of `T` and a queue of `Counted<T>`.
+pub trait QueueFamily {
+  type Queue<T: fmt::Debug + Ord>: PriorityQueue<Item = T> + Default;
+
+  /// The name of the queue implementation, as it appears in benchmark ids.
+  const NAME: &'static str;
+}
+
+/// The family of `WeakHeap`s.
+pub struct WeakHeapFamily;
+
+impl QueueFamily for WeakHeapFamily {
+  type Queue<T: fmt::Debug + Ord> = WeakHeap<T>;
+
+  const NAME: &'static str = "WeakHeap";
+}
+
+/// The family of `std::collections::BinaryHeap`s.
+pub struct BinaryHeapFamily;
+
+impl QueueFamily for BinaryHeapFamily {
+  type Queue<T: fmt::Debug + Ord> = StdBinaryHeap<T>;
+
+  const NAME: &'static str = "BinaryHeap";
+}
+
+/// The requirements on the element types used in benchmarks.
+pub trait BenchValue: HasDistribution + Clone + fmt::Debug + Ord + 'static {}
+
+impl<T: HasDistribution + Clone + fmt::Debug + Ord + 'static> BenchValue for T {}
+
+/// Returns the id of the benchmark of `workload` over `F::Queue<T>`, with
+/// inputs arranged according to `pattern`.
+fn bench_id<F: QueueFamily, T>(workload: &str, pattern: Pattern) -> String {
+  let type_name = any::type_name::<T>().rsplit("::").next().unwrap();
+  format!("{} ({}, {}) {}", workload, type_name, pattern.name(), F::NAME)
+}
+
+/// Returns a new queue holding `values`.
+pub fn fill<Q: PriorityQueue + Default, I: IntoIterator<Item = Q::Item>>(values: I) -> Q {
+  let mut queue = Q::default();
+  for v in values {
+    queue.push(v);
+  }
+  queue
+}
+
+/// Pops every element from `queue`, returning them in the order popped.
+pub fn drain<Q: PriorityQueue>(mut queue: Q) -> Vec<Q::Item> {
+  let mut drained = Vec::with_capacity(queue.len());
+  while let Some(x) = queue.pop() {
+    drained.push(x);
+  }
+  drained
+}
+
+/// Runs benchmarks that push and then pop sequences of each size in `sizes`,
+/// arranged according to `pattern`.
+///
+/// Before timing begins, each sequence is also sorted once with its values
+/// wrapped in `Counted`, and the number of comparisons made per element is
+/// printed. The timed runs use the unwrapped values.
+pub fn bench_sort<F: QueueFamily, T: BenchValue>(c: &mut Criterion, sizes: &[usize], pattern: Pattern) {
+  let id = bench_id::<F, T>("sort", pattern);
+  let mut all_values = HashMap::new();
+  let mut all_sorted = HashMap::new();
+  for &size in sizes {
+    let values: Vec<T> = get_pattern_values(pattern, size);
+    let sorted = {
+      let mut v = values.clone();
+      v.sort_by(|x, y| y.cmp(x));
+      v
+    };
+    let (_, counts) = ComparisonCounts::scope(|| {
+      drain(fill::<F::Queue<Counted<T>>, _>(values.iter().cloned().map(Counted)))
+    });
+    report_comparisons(&format!("{}/{}", id, size), size, counts);
+    all_values.insert(size, values);
+    all_sorted.insert(size, sorted);
+  }
+
+  c.bench_function_over_inputs(
+    &id,
+    move |b: &mut Bencher, size: &usize| {
+      let values = &all_values[size];
+      let sorted = &all_sorted[size];
+      b.iter(|| {
+        let heap_sorted = drain(fill::<F::Queue<T>, _>(values.iter().cloned()));
+        assert_eq!(heap_sorted, *sorted);
+      });
+    }, sizes.to_vec());
+}
+
+/// Runs benchmarks that push sequences of each size in `sizes`, arranged
+/// according to `pattern`, onto an empty heap. The number of comparisons made
+/// per push is printed before timing begins.
+pub fn bench_insert<F: QueueFamily, T: BenchValue>(c: &mut Criterion, sizes: &[usize], pattern: Pattern) {
+  let id = bench_id::<F, T>("insert", pattern);
+  let mut all_values = HashMap::new();
+  for &size in sizes {
+    let values: Vec<T> = get_pattern_values(pattern, size);
+    let (_, counts) = ComparisonCounts::scope(|| {
+      fill::<F::Queue<Counted<T>>, _>(values.iter().cloned().map(Counted))
+    });
+    report_comparisons(&format!("{}/{}", id, size), size, counts);
+    all_values.insert(size, values);
+  }
+
+  c.bench_function_over_inputs(
+    &id,
+    move |b: &mut Bencher, size: &usize| {
+      let values = &all_values[size];
+      b.iter_batched(|| values.clone(), fill::<F::Queue<T>, _>, BatchSize::LargeInput);
+    }, sizes.to_vec());
+}
+
+/// Runs benchmarks that pop every element from heaps holding sequences of each
+/// size in `sizes`, arranged according to `pattern`. The heaps are built
+/// outside of the timed section. The number of comparisons made per pop is
+/// printed before timing begins.
+pub fn bench_pop<F: QueueFamily, T: BenchValue>(c: &mut Criterion, sizes: &[usize], pattern: Pattern) {
+  let id = bench_id::<F, T>("pop", pattern);
+  let mut all_values = HashMap::new();
+  for &size in sizes {
+    let values: Vec<T> = get_pattern_values(pattern, size);
+    let heap = fill::<F::Queue<Counted<T>>, _>(values.iter().cloned().map(Counted));
+    let (_, counts) = ComparisonCounts::scope(|| drain(heap));
+    report_comparisons(&format!("{}/{}", id, size), size, counts);
+    all_values.insert(size, values);
+  }
+
+  c.bench_function_over_inputs(
+    &id,
+    move |b: &mut Bencher, size: &usize| {
+      let values = &all_values[size];
+      b.iter_batched(|| fill::<F::Queue<T>, _>(values.iter().cloned()), drain, BatchSize::LargeInput);
+    }, sizes.to_vec());
+}
+
+/// Runs benchmarks of the "hold" model over heaps of each size in `sizes`: a
+/// heap is filled to the given size outside of the timed section, and then the
+/// same number of hold operations (a pop followed by a push) are timed, so that
+/// the heap stays at a steady size. The number of comparisons made per hold
+/// operation is printed before timing begins. The initial and pushed elements
+/// together are arranged according to `pattern`.
+pub fn bench_hold<F: QueueFamily, T: BenchValue>(c: &mut Criterion, sizes: &[usize], pattern: Pattern) {
+  let id = bench_id::<F, T>("hold", pattern);
+  let mut all_values = HashMap::new();
+  for &size in sizes {
+    let mut initial: Vec<T> = get_pattern_values(pattern, 2 * size);
+    let pushed = initial.split_off(size);
+    let mut heap = fill::<F::Queue<Counted<T>>, _>(initial.iter().cloned().map(Counted));
+    let (_, counts) = ComparisonCounts::scope(|| {
+      for v in &pushed {
+        heap.pop();
+        heap.push(Counted(v.clone()));
+      }
+    });
+    report_comparisons(&format!("{}/{}", id, size), size, counts);
+    all_values.insert(size, (initial, pushed));
+  }
 
-    $bencher.bench_function_over_inputs(
-      &format!("sort ({}) {}", stringify!($type), stringify!($heap_factory)),
-      move |b: &mut criterion::Bencher, size: &usize| {
-        let values = all_values.get(&size).unwrap();
-        let sorted = all_sorted.get(size).unwrap();
-        b.iter(|| {
-          let mut heap = $heap_factory();
-          for v in values {
-            heap.push(v.clone());
-          }
-          let mut heap_sorted = Vec::with_capacity(heap.len());
-          while let Some(x) = heap.pop() {
-            heap_sorted.push(x);
-          }
-          assert_eq!(heap_sorted, *sorted);
-        });
-      }, $sizes);
-  })
+  c.bench_function_over_inputs(
+    &id,
+    move |b: &mut Bencher, size: &usize| {
+      let (initial, pushed) = &all_values[size];
+      b.iter_batched(|| {
+        (fill::<F::Queue<T>, _>(initial.iter().cloned()), pushed.clone())
+      }, |(mut heap, pushed)| {
+        for v in pushed {
+          heap.pop();
+          heap.push(v);
+        }
+        heap
+      }, BatchSize::LargeInput);
+    }, sizes.to_vec());
 }
 
-pub mod tiny;
-pub mod small;
-pub mod medium;
-pub mod large;
+pub mod concurrent;
+pub mod expensive;
+pub mod suite;
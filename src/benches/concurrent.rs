@@ -0,0 +1,112 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Criterion group definitions comparing the throughput of concurrent queues
+//! as the number of threads grows.
+//!
+//! Each benchmark prefills a queue and then has every thread perform the hold
+//! operation, a pop followed by a push, a fixed number of times. Throughput is
+//! reported in operations per second across all threads, so a queue that
+//! scales shows it rising with the thread count.
+
+use criterion::{BatchSize, Criterion, ParameterizedBenchmark, Throughput, criterion_group};
+use std::sync::{Arc, Barrier};
+use std::thread::{self, JoinHandle};
+use crate::SharedPriorityQueue;
+use crate::multiqueue::MultiQueue;
+use crate::sync::SyncWeakHeap;
+
+/// The number of elements in a queue before the threads start.
+const PREFILL: usize = 4096;
+
+/// The number of hold operations performed by each thread.
+const OPS_PER_THREAD: usize = 10_000;
+
+/// The thread counts benchmarked.
+const THREADS: [usize; 7] = [1, 2, 4, 8, 16, 32, 64];
+
+/// A queue that can be shared between the benchmark's threads.
+trait ConcurrentQueue: SharedPriorityQueue<Item = i32> + Sync {
+  fn for_threads(threads: usize) -> Self;
+}
+
+impl ConcurrentQueue for SyncWeakHeap<i32> {
+  fn for_threads(_: usize) -> Self { SyncWeakHeap::new() }
+}
+
+impl ConcurrentQueue for MultiQueue<i32> {
+  fn for_threads(threads: usize) -> Self { MultiQueue::new(threads) }
+}
+
+/// Threads that are ready to perform hold operations on a shared queue once
+/// they pass `start`.
+struct Holders<Q> {
+  queue: Arc<Q>,
+  start: Arc<Barrier>,
+  threads: Vec<JoinHandle<()>>,
+}
+
+/// Prefills a new queue of type `Q` and spawns `threads` threads that will each
+/// run `OPS_PER_THREAD` hold operations on it, waiting at a barrier so that
+/// none of this is timed.
+fn spawn_holders<Q>(threads: usize, prefill: &[i32], values: &Arc<Vec<Vec<i32>>>) -> Holders<Q>
+where Q: ConcurrentQueue + Send + 'static {
+  let queue = Arc::new(Q::for_threads(threads));
+  for &value in prefill {
+    queue.push(value).unwrap();
+  }
+  let start = Arc::new(Barrier::new(threads + 1));
+  let threads = (0..threads).map(|t| {
+    let (queue, start, values) = (Arc::clone(&queue), Arc::clone(&start), Arc::clone(values));
+    thread::spawn(move || {
+      start.wait();
+      for &value in &values[t] {
+        criterion::black_box(queue.pop());
+        queue.push(value).unwrap();
+      }
+    })
+  }).collect();
+  Holders { queue, start, threads, }
+}
+
+/// Releases the holders and waits for them to finish. The queue is returned so
+/// that it is dropped outside the measurement.
+fn hold<Q>(holders: Holders<Q>) -> Arc<Q> {
+  holders.start.wait();
+  for thread in holders.threads {
+    thread.join().unwrap();
+  }
+  holders.queue
+}
+
+fn hold_throughput(c: &mut Criterion) {
+  let prefill: Vec<i32> = crate::benches::get_values(PREFILL);
+  let max_threads = *THREADS.iter().max().unwrap();
+  let values: Arc<Vec<Vec<i32>>> = Arc::new(crate::benches::get_values(max_threads * OPS_PER_THREAD)
+    .chunks(OPS_PER_THREAD).map(<[i32]>::to_vec).collect());
+  let (sync_prefill, sync_values) = (prefill.clone(), Arc::clone(&values));
+  let benchmark = ParameterizedBenchmark::new("SyncWeakHeap", move |b, &threads: &usize| {
+    b.iter_batched(|| spawn_holders::<SyncWeakHeap<i32>>(threads, &sync_prefill, &sync_values),
+                   hold, BatchSize::PerIteration);
+  }, THREADS.to_vec())
+    .with_function("MultiQueue", move |b, &threads: &usize| {
+      b.iter_batched(|| spawn_holders::<MultiQueue<i32>>(threads, &prefill, &values),
+                     hold, BatchSize::PerIteration);
+    })
+    .throughput(|&threads| Throughput::Elements((threads * OPS_PER_THREAD) as u32))
+    .sample_size(20);
+  c.bench("concurrent hold", benchmark);
+}
+
+criterion_group!(scaling, hold_throughput);
@@ -0,0 +1,221 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Criterion group definitions for element types whose comparisons have a
+//! tunable cost.
+//!
+//! Each group sweeps the cost of a comparison while sorting a fixed number of
+//! elements. Before the criterion benchmarks run, the sweep is timed once
+//! directly and a table is printed showing the comparison cost at which
+//! `WeakHeap` overtakes `BinaryHeap`, if it does.
+
+use criterion::{Criterion, criterion_group};
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hint;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::time::{Duration, Instant};
+use crate::{StdBinaryHeap, WeakHeap};
+use crate::benches::{ComparisonCounts, Counted, drain, fill};
+
+/// The number of elements sorted by each benchmark.
+const SIZE: usize = 1024;
+
+/// The number of times each sort is repeated when finding the crossover point.
+/// The fastest repetition is used.
+const REPETITIONS: usize = 5;
+
+static SPIN_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// An `i32` that busy-waits for the duration set by `Spin::set_cost` on every
+/// comparison. The wait is measured with `Instant`, so its resolution is
+/// limited by the cost of reading the clock.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Spin(pub i32);
+
+impl Spin {
+  /// Sets the time that every comparison will take, in nanoseconds.
+  pub fn set_cost(nanos: u64) {
+    SPIN_NANOS.store(nanos, AtomicOrdering::SeqCst);
+  }
+
+  fn spin() {
+    let nanos = SPIN_NANOS.load(AtomicOrdering::Relaxed);
+    if nanos > 0 {
+      let start = Instant::now();
+      while start.elapsed() < Duration::from_nanos(nanos) {
+        hint::spin_loop();
+      }
+    }
+  }
+}
+
+impl PartialOrd for Spin {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Spin {
+  fn cmp(&self, other: &Self) -> Ordering {
+    Spin::spin();
+    self.0.cmp(&other.0)
+  }
+}
+
+/// A string with a long prefix that is shared by every value, so that each
+/// comparison must scan the whole prefix.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct SharedPrefix(pub String);
+
+/// A string that is ordered in the manner of a collation key: case and
+/// punctuation are ignored, and are only used to break ties. Comparisons map
+/// each character as they go, as a locale-aware collator would.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Collated(pub String);
+
+impl Collated {
+  fn key<'a>(&'a self) -> impl Iterator<Item = char> + 'a {
+    self.0.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase)
+  }
+}
+
+impl PartialOrd for Collated {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Collated {
+  fn cmp(&self, other: &Self) -> Ordering {
+    self.key().cmp(other.key()).then_with(|| self.0.cmp(&other.0))
+  }
+}
+
+/// Returns `SIZE` random suffixes, generated deterministically.
+fn suffixes() -> Vec<String> {
+  let mut rng = crate::benches::seeded_rng();
+  (0..SIZE).map(|_| rng.sample_iter(&Alphanumeric).take(8).collect()).collect()
+}
+
+fn spin_values(_: usize) -> Vec<Spin> {
+  crate::benches::get_values::<i32>(SIZE).into_iter().map(Spin).collect()
+}
+
+/// Returns values that share a prefix of `len` bytes.
+fn shared_prefix_values(len: usize) -> Vec<SharedPrefix> {
+  let prefix = "a".repeat(len);
+  suffixes().into_iter().map(|s| SharedPrefix(format!("{}{}", prefix, s))).collect()
+}
+
+/// Returns values whose collation keys share a prefix of `len` characters,
+/// but whose prefixes differ in case and punctuation.
+fn collated_values(len: usize) -> Vec<Collated> {
+  let mut rng = crate::benches::seeded_rng();
+  suffixes().into_iter().map(|s| {
+    let mut value = String::with_capacity(2 * len + s.len());
+    for c in "collation".chars().cycle().take(len) {
+      value.push(if rng.gen() { c.to_ascii_uppercase() } else { c });
+      if rng.gen_range(0, 4) == 0 {
+        value.push('-');
+      }
+    }
+    value.push_str(&s);
+    Collated(value)
+  }).collect()
+}
+
+fn sort_binary<T: Clone + fmt::Debug + Ord>(values: &[T]) -> Vec<T> {
+  drain(fill::<StdBinaryHeap<T>, _>(values.iter().cloned()))
+}
+
+fn sort_weak<T: Clone + fmt::Debug + Ord>(values: &[T]) -> Vec<T> {
+  drain(fill::<WeakHeap<T>, _>(values.iter().cloned()))
+}
+
+/// Returns the shortest of `REPETITIONS` runs of `sort` on `values`.
+fn fastest<T, F: Fn(&[T]) -> Vec<T>>(values: &[T], sort: F) -> Duration {
+  (0..REPETITIONS).map(|_| {
+    let start = Instant::now();
+    criterion::black_box(sort(values));
+    start.elapsed()
+  }).min().unwrap()
+}
+
+/// Returns the number of comparisons per element that `sort` makes on
+/// `values`.
+fn comparisons_per_element<T: Clone, F: Fn(&[Counted<T>]) -> Vec<Counted<T>>>(values: &[T], sort: F) -> f64 {
+  let counted: Vec<Counted<T>> = values.iter().cloned().map(Counted).collect();
+  let (_, counts) = ComparisonCounts::scope(|| sort(&counted));
+  counts.total() as f64 / values.len() as f64
+}
+
+/// Times both heaps at each cost in `costs` and prints the results, along with
+/// the lowest cost from which `WeakHeap` is faster than `BinaryHeap` at every
+/// cost that was measured.
+fn report_crossover<T, V>(name: &str, unit: &str, costs: &[usize], values: V)
+where T: Clone + fmt::Debug + Ord, V: Fn(usize) -> Vec<T> {
+  println!("{} (n = {}):", name, SIZE);
+  println!("  {:>10} {:>12} {:>12} {:>10} {:>10}", unit, "binary", "weak", "binary cmp", "weak cmp");
+  let mut crossover = None;
+  for &cost in costs {
+    let values = values(cost);
+    let binary = fastest(&values, sort_binary);
+    let weak = fastest(&values, sort_weak);
+    println!("  {:>10} {:>12?} {:>12?} {:>10.2} {:>10.2}", cost, binary, weak,
+             comparisons_per_element(&values, sort_binary),
+             comparisons_per_element(&values, sort_weak));
+    if weak < binary {
+      crossover = crossover.or(Some(cost));
+    } else {
+      crossover = None;
+    }
+  }
+  match crossover {
+    Some(cost) => println!("{}: WeakHeap is faster from {} {}", name, cost, unit),
+    None => println!("{}: WeakHeap is not faster at the largest cost measured", name),
+  }
+}
+
+/// Defines a function that benchmarks sorting with both heaps at each of
+/// `$costs`, after printing the crossover point.
+macro_rules! sweep_bench {
+  ($name: ident, $unit: expr, $costs: expr, $values: expr, $setup: expr) => (
+    fn $name(c: &mut Criterion) {
+      let name = stringify!($name);
+      report_crossover(name, $unit, &$costs, |cost| {
+        $setup(cost);
+        $values(cost)
+      });
+      c.bench_function_over_inputs(&format!("{} BinaryHeap::new", name), |b, &cost: &usize| {
+        $setup(cost);
+        let values = $values(cost);
+        b.iter(|| sort_binary(&values));
+      }, $costs.to_vec());
+      c.bench_function_over_inputs(&format!("{} WeakHeap::new", name), |b, &cost: &usize| {
+        $setup(cost);
+        let values = $values(cost);
+        b.iter(|| sort_weak(&values));
+      }, $costs.to_vec());
+    }
+  )
+}
+
+sweep_bench!(spin, "ns", [0, 10, 20, 50, 100, 200, 500, 1000], spin_values, |cost| Spin::set_cost(cost as u64));
+sweep_bench!(shared_prefix, "bytes", [0, 16, 64, 256, 1024, 4096, 16384], shared_prefix_values, |_| ());
+sweep_bench!(collated, "chars", [0, 4, 16, 64, 256], collated_values, |_| ());
+
+criterion_group!(sweep, spin, shared_prefix, collated);
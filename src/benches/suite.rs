@@ -0,0 +1,241 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The benchmark suite run by `cargo bench --bench heaps`.
+//!
+//! Every combination of size class, element type, heap implementation,
+//! workload and input pattern is run by default. Each dimension can be narrowed
+//! with an environment variable holding a comma-separated list of names:
+//!
+//! * `WEAK_HEAP_BENCH_SIZES`: `tiny`, `small`, `medium`, `large`
+//! * `WEAK_HEAP_BENCH_TYPES`: `i32`, `i128`, `u32768`
+//! * `WEAK_HEAP_BENCH_HEAPS`: `binary`, `weak`
+//! * `WEAK_HEAP_BENCH_WORKLOADS`: `sort`, `insert`, `pop`, `hold`
+//! * `WEAK_HEAP_BENCH_PATTERNS`: see `Pattern::name`
+//!
+//! Criterion's filter argument is applied to benchmark ids as usual, but the
+//! inputs (and comparison counts) for a benchmark are computed even when the
+//! filter excludes it, so prefer the environment variables for coarse
+//! selection.
+//!
+//! Every size in each size class is benchmarked, unless
+//! `WEAK_HEAP_BENCH_STRIDED` is set, in which case each class is sampled at a
+//! fixed stride for a quicker, coarser run. The medium and large classes
+//! take fewer criterion samples, and fewer still for `U32768`, whose elements
+//! are expensive to clone and move. `WEAK_HEAP_BENCH_SAMPLE_SIZE` overrides the
+//! number of samples for every benchmark.
+
+use criterion::Criterion;
+use std::env;
+use std::time::Duration;
+use crate::benches::{bench_hold, bench_insert, bench_pop, bench_sort};
+use crate::benches::{BenchValue, BinaryHeapFamily, Pattern, QueueFamily, U32768, WeakHeapFamily};
+
+/// A range of heap sizes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SizeClass {
+  Tiny,
+  Small,
+  Medium,
+  Large,
+}
+
+impl SizeClass {
+  pub const ALL: [SizeClass; 4] = [SizeClass::Tiny, SizeClass::Small, SizeClass::Medium, SizeClass::Large];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      SizeClass::Tiny => "tiny",
+      SizeClass::Small => "small",
+      SizeClass::Medium => "medium",
+      SizeClass::Large => "large",
+    }
+  }
+
+  /// Returns the sizes in this class that are benchmarked. If `strided` is
+  /// true, only sizes at a fixed stride through the class are returned.
+  pub fn sizes(self, strided: bool) -> Vec<usize> {
+    let (sizes, stride) = match self {
+      SizeClass::Tiny => (1..128, 8),
+      SizeClass::Small => (128..256, 16),
+      SizeClass::Medium => (256..1024, 64),
+      SizeClass::Large => (1024..4096, 256),
+    };
+    sizes.step_by(if strided { stride } else { 1 }).collect()
+  }
+}
+
+/// The type of the elements stored in a heap.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ElementType {
+  I32,
+  I128,
+  U32768,
+}
+
+impl ElementType {
+  pub const ALL: [ElementType; 3] = [ElementType::I32, ElementType::I128, ElementType::U32768];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      ElementType::I32 => "i32",
+      ElementType::I128 => "i128",
+      ElementType::U32768 => "u32768",
+    }
+  }
+}
+
+/// A heap implementation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HeapKind {
+  Binary,
+  Weak,
+}
+
+impl HeapKind {
+  pub const ALL: [HeapKind; 2] = [HeapKind::Binary, HeapKind::Weak];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      HeapKind::Binary => "binary",
+      HeapKind::Weak => "weak",
+    }
+  }
+}
+
+/// The operations performed by a benchmark. See `bench_sort`, `bench_insert`,
+/// `bench_pop` and `bench_hold`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Workload {
+  Sort,
+  Insert,
+  Pop,
+  Hold,
+}
+
+impl Workload {
+  pub const ALL: [Workload; 4] = [Workload::Sort, Workload::Insert, Workload::Pop, Workload::Hold];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Workload::Sort => "sort",
+      Workload::Insert => "insert",
+      Workload::Pop => "pop",
+      Workload::Hold => "hold",
+    }
+  }
+}
+
+/// The benchmarks to run.
+#[derive(Clone, Debug)]
+pub struct Selection {
+  pub size_classes: Vec<SizeClass>,
+  pub types: Vec<ElementType>,
+  pub heaps: Vec<HeapKind>,
+  pub workloads: Vec<Workload>,
+  pub patterns: Vec<Pattern>,
+  /// Whether to benchmark only sizes at a fixed stride through each size
+  /// class, rather than every size.
+  pub strided: bool,
+  /// If set, the number of samples to take for every benchmark.
+  pub sample_size: Option<usize>,
+}
+
+impl Selection {
+  /// Reads the selection from the environment variables described in the
+  /// module documentation. Panics if a variable holds an unknown name.
+  pub fn from_env() -> Self {
+    Selection {
+      size_classes: parse_list("WEAK_HEAP_BENCH_SIZES", &SizeClass::ALL, SizeClass::name),
+      types: parse_list("WEAK_HEAP_BENCH_TYPES", &ElementType::ALL, ElementType::name),
+      heaps: parse_list("WEAK_HEAP_BENCH_HEAPS", &HeapKind::ALL, HeapKind::name),
+      workloads: parse_list("WEAK_HEAP_BENCH_WORKLOADS", &Workload::ALL, Workload::name),
+      patterns: parse_list("WEAK_HEAP_BENCH_PATTERNS", &Pattern::ALL, Pattern::name),
+      strided: env::var_os("WEAK_HEAP_BENCH_STRIDED").is_some(),
+      sample_size: env::var("WEAK_HEAP_BENCH_SAMPLE_SIZE").ok().map(|n| {
+        n.parse().unwrap_or_else(|_| panic!("WEAK_HEAP_BENCH_SAMPLE_SIZE must be a number, not {:?}", n))
+      }),
+    }
+  }
+
+  /// Returns a `Criterion` configured for benchmarks of `element_type` in
+  /// `size_class`.
+  fn criterion(&self, size_class: SizeClass, element_type: ElementType) -> Criterion {
+    let (sample_size, warm_up, measurement) = match (size_class, element_type) {
+      (SizeClass::Tiny, _) | (SizeClass::Small, _) => (100, 3, 5),
+      (_, ElementType::U32768) => (10, 1, 5),
+      _ => (30, 1, 3),
+    };
+    Criterion::default()
+      .sample_size(self.sample_size.unwrap_or(sample_size))
+      .warm_up_time(Duration::from_secs(warm_up))
+      .measurement_time(Duration::from_secs(measurement))
+      .configure_from_args()
+  }
+}
+
+/// Reads a comma-separated list of names from the environment variable `var`,
+/// returning `all` if it is unset.
+fn parse_list<T: Copy>(var: &str, all: &[T], name: fn(T) -> &'static str) -> Vec<T> {
+  let list = match env::var(var) {
+    Ok(list) => list,
+    Err(_) => return all.to_vec(),
+  };
+  list.split(',').map(str::trim).filter(|s| !s.is_empty()).map(|s| {
+    *all.iter().find(|x| name(**x) == s).unwrap_or_else(|| {
+      let names: Vec<&str> = all.iter().map(|x| name(*x)).collect();
+      panic!("unknown name {:?} in {}; expected one of {}", s, var, names.join(", "))
+    })
+  }).collect()
+}
+
+fn run_workload<F: QueueFamily, T: BenchValue>(c: &mut Criterion, workload: Workload, sizes: &[usize],
+                                                pattern: Pattern) {
+  match workload {
+    Workload::Sort => bench_sort::<F, T>(c, sizes, pattern),
+    Workload::Insert => bench_insert::<F, T>(c, sizes, pattern),
+    Workload::Pop => bench_pop::<F, T>(c, sizes, pattern),
+    Workload::Hold => bench_hold::<F, T>(c, sizes, pattern),
+  }
+}
+
+fn run_one<F: QueueFamily>(c: &mut Criterion, workload: Workload, element_type: ElementType, sizes: &[usize],
+                           pattern: Pattern) {
+  match element_type {
+    ElementType::I32 => run_workload::<F, i32>(c, workload, sizes, pattern),
+    ElementType::I128 => run_workload::<F, i128>(c, workload, sizes, pattern),
+    ElementType::U32768 => run_workload::<F, U32768>(c, workload, sizes, pattern),
+  }
+}
+
+/// Runs the benchmarks in `selection`.
+pub fn run(selection: &Selection) {
+  for &size_class in &selection.size_classes {
+    let sizes = size_class.sizes(selection.strided);
+    for &element_type in &selection.types {
+      let mut c = selection.criterion(size_class, element_type);
+      for &workload in &selection.workloads {
+        for &heap in &selection.heaps {
+          for &pattern in &selection.patterns {
+            match heap {
+              HeapKind::Binary => run_one::<BinaryHeapFamily>(&mut c, workload, element_type, &sizes, pattern),
+              HeapKind::Weak => run_one::<WeakHeapFamily>(&mut c, workload, element_type, &sizes, pattern),
+            }
+          }
+        }
+      }
+    }
+  }
+  Criterion::default().configure_from_args().final_summary();
+}
@@ -0,0 +1,214 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sorts lines of text that may not fit in memory. See
+//! `weak_heap::external_sort` for how.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use weak_heap::external_sort::{sort, SortConfig};
+use weak_heap::keys::Comparison;
+
+const USAGE: &str = "\
+usage: weak-heap-sort [options] [file...]
+
+Sorts the lines of the given files, or of standard input, and writes them to
+standard output.
+
+options:
+  -k, --key FIELD            sort by the 1-based FIELD instead of the whole line
+  -t, --field-separator SEP  separate fields by the byte SEP instead of whitespace
+  -n, --numeric              compare keys as numbers instead of lexically
+  -r, --reverse              write the greatest lines first
+  -S, --buffer-size SIZE     hold about SIZE bytes of lines in memory; SIZE may
+                             end in K, M or G (default 64M)
+  -T, --temporary-directory DIR
+                             write temporary files in DIR
+      --fan-in N             merge at most N runs at once (default 64)
+  -o, --output FILE          write to FILE instead of standard output; FILE may
+                             also be one of the inputs
+  -h, --help                 show this message
+";
+
+struct Args {
+  config: SortConfig,
+  output: Option<PathBuf>,
+  inputs: Vec<PathBuf>,
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+  let (digits, shift) = match s.chars().last() {
+    Some('K') | Some('k') => (&s[..s.len() - 1], 10),
+    Some('M') | Some('m') => (&s[..s.len() - 1], 20),
+    Some('G') | Some('g') => (&s[..s.len() - 1], 30),
+    _ => (s, 0),
+  };
+  digits.parse::<usize>().ok()
+    .and_then(|n| n.checked_mul(1 << shift))
+    .ok_or_else(|| format!("invalid size '{}'", s))
+}
+
+fn parse_args() -> Result<Args, String> {
+  let mut args = Args { config: SortConfig::default(), output: None, inputs: Vec::new(), };
+  let mut argv = env::args().skip(1);
+  while let Some(arg) = argv.next() {
+    let mut value = |name: &str| argv.next().ok_or_else(|| format!("{} requires a value", name));
+    match arg.as_str() {
+      "-k" | "--key" => {
+        let v = value(&arg)?;
+        match v.parse::<usize>() {
+          Ok(field) if field > 0 => args.config.key.field = Some(field),
+          _ => return Err(format!("invalid field '{}'", v)),
+        }
+      },
+      "-t" | "--field-separator" => {
+        let v = value(&arg)?;
+        match v.as_bytes() {
+          [b] => args.config.key.separator = Some(*b),
+          _ => return Err(format!("separator '{}' is not a single byte", v)),
+        }
+      },
+      "-n" | "--numeric" => args.config.key.comparison = Comparison::Numeric,
+      "-r" | "--reverse" => args.config.key.reverse = true,
+      "-S" | "--buffer-size" => args.config.memory_limit = parse_size(&value(&arg)?)?,
+      "-T" | "--temporary-directory" => args.config.temp_dir = PathBuf::from(value(&arg)?),
+      "--fan-in" => {
+        let v = value(&arg)?;
+        match v.parse::<usize>() {
+          Ok(n) if n >= 2 => args.config.fan_in = n,
+          _ => return Err(format!("invalid fan-in '{}'", v)),
+        }
+      },
+      "-o" | "--output" => args.output = Some(PathBuf::from(value(&arg)?)),
+      "-h" | "--help" => {
+        print!("{}", USAGE);
+        process::exit(0);
+      },
+      "-" => args.inputs.push(PathBuf::from(arg)),
+      _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+      _ => args.inputs.push(PathBuf::from(arg)),
+    }
+  }
+  Ok(args)
+}
+
+/// Wraps a reader, appending a newline if its input is non-empty and does not
+/// end with one, so that concatenated files do not run lines together.
+struct Terminated<R: Read> {
+  inner: R,
+  last: Option<u8>,
+  done: bool,
+}
+
+impl<R: Read> Read for Terminated<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.done || buf.is_empty() {
+      return Ok(0);
+    }
+    let n = self.inner.read(buf)?;
+    if n > 0 {
+      self.last = Some(buf[n - 1]);
+      return Ok(n);
+    }
+    self.done = true;
+    match self.last {
+      Some(b) if b != b'\n' => {
+        buf[0] = b'\n';
+        Ok(1)
+      },
+      _ => Ok(0),
+    }
+  }
+}
+
+/// Writes to a temporary file beside `path` and renames it over `path` once
+/// `write` succeeds, so that `path` is left untouched until every input has
+/// been read, even if it is also an input.
+fn write_replacing<F>(path: &Path, write: F) -> io::Result<()>
+where F: FnOnce(&mut BufWriter<File>) -> io::Result<()> {
+  let mut name = OsString::from(".");
+  name.push(path.file_name().unwrap_or_default());
+  name.push(format!(".{}.tmp", process::id()));
+  let temp = path.with_file_name(name);
+  let result = File::create(&temp).and_then(|file| {
+    let mut out = BufWriter::new(file);
+    write(&mut out)?;
+    out.flush()?;
+    fs::rename(&temp, path)
+  });
+  if result.is_err() {
+    let _ = fs::remove_file(&temp);
+  }
+  result
+}
+
+fn run(args: Args) -> io::Result<()> {
+  let mut input: Box<dyn Read> = Box::new(io::empty());
+  if args.inputs.is_empty() {
+    input = Box::new(io::stdin());
+  }
+  for path in args.inputs.iter().rev() {
+    let next: Box<dyn Read> = if path.to_str() == Some("-") {
+      Box::new(io::stdin())
+    } else {
+      Box::new(File::open(path).map_err(|e| {
+        io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+      })?)
+    };
+    input = Box::new(Terminated { inner: next, last: None, done: false, }.chain(input));
+  }
+  let input = BufReader::with_capacity(1 << 16, input);
+  match args.output {
+    Some(ref path) => write_replacing(path, |out| sort(input, out, &args.config).map(|_| ())),
+    None => sort(input, &mut BufWriter::new(io::stdout().lock()), &args.config).map(|_| ()),
+  }
+}
+
+fn main() {
+  let args = parse_args().unwrap_or_else(|e| {
+    eprintln!("weak-heap-sort: {}\n\n{}", e, USAGE);
+    process::exit(2);
+  });
+  match run(args) {
+    Ok(()) => (),
+    // The reader of our output, such as `head`, has seen enough.
+    Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+    Err(e) => {
+      eprintln!("weak-heap-sort: {}", e);
+      process::exit(1);
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use weak_heap::external_sort::SortConfig;
+  use super::{run, Args};
+
+  #[test]
+  fn output_may_be_an_input() {
+    let path = env::temp_dir().join(format!("weak-heap-sort-in-place-{}", std::process::id()));
+    fs::write(&path, "pear\napple\nfig\n").unwrap();
+    let args = Args { config: SortConfig::default(), output: Some(path.clone()), inputs: vec![path.clone()], };
+    run(args).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "apple\nfig\npear\n");
+    fs::remove_file(&path).unwrap();
+  }
+}
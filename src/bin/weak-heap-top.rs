@@ -0,0 +1,248 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Selects the top or bottom lines of standard input by a key, in memory
+//! proportional to the number of lines selected. See `weak_heap::select`.
+
+use std::cmp::Reverse;
+use std::env;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::mem;
+use std::process;
+use weak_heap::keys::{Comparison, KeySpec, KeyedLine};
+use weak_heap::select::TopK;
+
+const USAGE: &str = "\
+usage: weak-heap-top [options]
+
+Writes the COUNT lines of standard input with the greatest keys to standard
+output. Among lines with equal keys, the earliest are selected.
+
+options:
+  -c, --count COUNT          select COUNT lines (default 10)
+  -b, --bottom               select the lines with the least keys instead
+  -f, --field FIELD          use the 1-based FIELD as the key instead of the
+                             whole line
+  -t, --field-separator SEP  separate fields by the byte SEP instead of whitespace
+  -e, --regex PATTERN        use the first capture group of PATTERN, or else the
+                             whole match, within the field or line as the key
+  -n, --numeric              compare keys as numbers instead of lexically
+  -o, --order ORDER          write the selected lines in ORDER: `ranked` (the
+                             default, best first), `reversed` or `input`
+  -h, --help                 show this message
+";
+
+/// The order in which the selected lines are written.
+enum Order {
+  Ranked,
+  Reversed,
+  Input,
+}
+
+struct Args {
+  count: usize,
+  key: KeySpec,
+  order: Order,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut argv: I) -> Result<Args, String> {
+  let mut args = Args { count: 10, key: KeySpec::default(), order: Order::Ranked, };
+  while let Some(arg) = argv.next() {
+    let mut value = |name: &str| argv.next().ok_or_else(|| format!("{} requires a value", name));
+    match arg.as_str() {
+      "-c" | "--count" => {
+        let v = value(&arg)?;
+        args.count = v.parse().map_err(|_| format!("invalid count '{}'", v))?;
+      },
+      "-b" | "--bottom" => args.key.reverse = true,
+      "-f" | "--field" => {
+        let v = value(&arg)?;
+        match v.parse::<usize>() {
+          Ok(field) if field > 0 => args.key.field = Some(field),
+          _ => return Err(format!("invalid field '{}'", v)),
+        }
+      },
+      "-t" | "--field-separator" => {
+        let v = value(&arg)?;
+        match v.as_bytes() {
+          [b] => args.key.separator = Some(*b),
+          _ => return Err(format!("separator '{}' is not a single byte", v)),
+        }
+      },
+      "-e" | "--regex" => set_pattern(&mut args.key, &value(&arg)?)?,
+      "-n" | "--numeric" => args.key.comparison = Comparison::Numeric,
+      "-o" | "--order" => {
+        args.order = match value(&arg)?.as_str() {
+          "ranked" => Order::Ranked,
+          "reversed" => Order::Reversed,
+          "input" => Order::Input,
+          v => return Err(format!("unknown order '{}'", v)),
+        }
+      },
+      "-h" | "--help" => {
+        print!("{}", USAGE);
+        process::exit(0);
+      },
+      _ => return Err(format!("unknown argument '{}'", arg)),
+    }
+  }
+  Ok(args)
+}
+
+#[cfg(feature = "regex")]
+fn set_pattern(key: &mut KeySpec, pattern: &str) -> Result<(), String> {
+  let pattern = regex::bytes::Regex::new(pattern).map_err(|e| e.to_string())?;
+  key.pattern = Some(pattern);
+  Ok(())
+}
+
+#[cfg(not(feature = "regex"))]
+fn set_pattern(_key: &mut KeySpec, _pattern: &str) -> Result<(), String> {
+  Err("--regex requires building with the `regex` feature".to_string())
+}
+
+/// Writes the selected lines of `input` to `output`.
+fn run<R: BufRead, W: Write>(args: Args, mut input: R, mut output: W) -> io::Result<()> {
+  // Lines are ranked by key, and then by earliest position in the input.
+  let mut top: TopK<(KeyedLine, Reverse<u64>)> = TopK::new(args.count);
+  let mut line = Vec::new();
+  let mut position = 0;
+  loop {
+    if input.read_until(b'\n', &mut line)? == 0 {
+      break;
+    }
+    if line.last() == Some(&b'\n') {
+      line.pop();
+    }
+    // A rejected line is dropped immediately, so at most `count` lines are
+    // held at once.
+    top.push((args.key.keyed(mem::take(&mut line)), Reverse(position)));
+    position += 1;
+  }
+
+  let mut selected = top.into_sorted_vec();
+  match args.order {
+    Order::Ranked => (),
+    Order::Reversed => selected.reverse(),
+    Order::Input => selected.sort_by_key(|(_, position)| Reverse(*position)),
+  }
+  for (line, _) in selected {
+    output.write_all(line.line())?;
+    output.write_all(b"\n")?;
+  }
+  output.flush()
+}
+
+fn main() {
+  let args = parse_args(env::args().skip(1)).unwrap_or_else(|e| {
+    eprintln!("weak-heap-top: {}\n\n{}", e, USAGE);
+    process::exit(2);
+  });
+  let stdin = io::stdin();
+  let stdout = io::stdout();
+  match run(args, stdin.lock(), BufWriter::new(stdout.lock())) {
+    Ok(()) => (),
+    // The reader of our output, such as `head`, has seen enough.
+    Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+    Err(e) => {
+      eprintln!("weak-heap-top: {}", e);
+      process::exit(1);
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse_args, run, Order};
+
+  /// Runs with the arguments `argv` on `input` and returns the output.
+  fn top(argv: &[&str], input: &str) -> String {
+    let args = parse_args(argv.iter().map(|arg| arg.to_string())).unwrap();
+    let mut output = Vec::new();
+    run(args, input.as_bytes(), &mut output).unwrap();
+    String::from_utf8(output).unwrap()
+  }
+
+  fn parse_error(argv: &[&str]) -> String {
+    match parse_args(argv.iter().map(|arg| arg.to_string())) {
+      Ok(_) => panic!("{:?} parsed", argv),
+      Err(e) => e,
+    }
+  }
+
+  #[test]
+  fn parses_options() {
+    let args = parse_args(Vec::new().into_iter()).unwrap();
+    assert_eq!(args.count, 10);
+    assert!(!args.key.reverse);
+    assert!(matches!(args.order, Order::Ranked));
+    let argv = ["--count", "3", "-b", "-f", "2", "-t", ",", "-n", "-o", "input"];
+    let args = parse_args(argv.iter().map(|arg| arg.to_string())).unwrap();
+    assert_eq!(args.count, 3);
+    assert!(args.key.reverse);
+    assert_eq!(args.key.field, Some(2));
+    assert_eq!(args.key.separator, Some(b','));
+    assert!(matches!(args.order, Order::Input));
+  }
+
+  #[test]
+  fn rejects_bad_options() {
+    assert_eq!(parse_error(&["-c"]), "-c requires a value");
+    assert_eq!(parse_error(&["-c", "many"]), "invalid count 'many'");
+    assert_eq!(parse_error(&["-f", "0"]), "invalid field '0'");
+    assert_eq!(parse_error(&["-t", "::"]), "separator '::' is not a single byte");
+    assert_eq!(parse_error(&["-o", "sideways"]), "unknown order 'sideways'");
+    assert_eq!(parse_error(&["--frobnicate"]), "unknown argument '--frobnicate'");
+  }
+
+  #[test]
+  fn selects_top_or_bottom_count() {
+    let input = "pear\napple\nfig\nplum\nkiwi\n";
+    assert_eq!(top(&["-c", "2"], input), "plum\npear\n");
+    assert_eq!(top(&["-c", "2", "-b"], input), "apple\nfig\n");
+    assert_eq!(top(&["-c", "0"], input), "");
+    assert_eq!(top(&["-c", "9"], input), "plum\npear\nkiwi\nfig\napple\n");
+  }
+
+  #[test]
+  fn keys_by_numeric_field() {
+    let input = "a 10\nb 9\nc 100\nd 9\n";
+    assert_eq!(top(&["-c", "2", "-f", "2", "-n"], input), "c 100\na 10\n");
+    // Among equal keys, the earliest lines are selected.
+    assert_eq!(top(&["-c", "2", "-f", "2", "-n", "-b"], input), "b 9\nd 9\n");
+  }
+
+  #[test]
+  fn writes_in_requested_order() {
+    let input = "3\n1\n4\n1\n5\n9\n2\n6\n";
+    assert_eq!(top(&["-c", "3", "-n"], input), "9\n6\n5\n");
+    assert_eq!(top(&["-c", "3", "-n", "-o", "reversed"], input), "5\n6\n9\n");
+    assert_eq!(top(&["-c", "3", "-n", "-o", "input"], input), "5\n9\n6\n");
+  }
+
+  #[cfg(feature = "regex")]
+  #[test]
+  fn keys_by_regex() {
+    let input = "id=7 x\nid=12 y\nid=3 z\n";
+    assert_eq!(top(&["-c", "1", "-e", "id=([0-9]+)", "-n"], input), "id=12 y\n");
+    // Without a capture group the whole match is the key.
+    assert_eq!(top(&["-c", "1", "-e", "[xyz]"], input), "id=3 z\n");
+  }
+
+  #[cfg(not(feature = "regex"))]
+  #[test]
+  fn regex_requires_feature() {
+    assert_eq!(parse_error(&["-e", "x"]), "--regex requires building with the `regex` feature");
+  }
+}
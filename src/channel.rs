@@ -0,0 +1,276 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A multi-producer, single-consumer channel whose receiver yields the greatest
+//! buffered message first rather than the oldest.
+//!
+//! Apart from the order of messages, the channel behaves like
+//! `std::sync::mpsc::channel`, and uses its error types: sending fails once the
+//! `Receiver` is dropped, and receiving fails once every `Sender` is dropped and
+//! the buffered messages are exhausted.
+//!
+//! ```rust
+//! # use std::thread;
+//! # use weak_heap::channel::priority_channel;
+//! # fn main() {
+//! let (tx, rx) = priority_channel();
+//! let handle = thread::spawn(move || {
+//!   for x in &[2, 7, 1, 8] {
+//!     tx.send(*x).unwrap();
+//!   }
+//! });
+//! handle.join().unwrap();
+//! assert_eq!(rx.iter().collect::<Vec<_>>(), vec![8, 7, 2, 1]);
+//! # }
+//! ```
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+pub use std::sync::mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError};
+use crate::sync::{PopTimeoutError, SyncWeakHeap, TryPopError};
+
+#[derive(Debug)]
+struct Shared<T: Debug + Ord> {
+  queue: SyncWeakHeap<T>,
+  senders: AtomicUsize,
+}
+
+/// Creates a new priority channel, returning its sending and receiving halves.
+pub fn priority_channel<T: Debug + Ord>() -> (Sender<T>, Receiver<T>) {
+  let shared = Arc::new(Shared { queue: SyncWeakHeap::new(), senders: AtomicUsize::new(1), });
+  (Sender { shared: Arc::clone(&shared) }, Receiver { shared })
+}
+
+/// The sending half of a priority channel. It may be cloned to send from
+/// several threads.
+#[derive(Debug)]
+pub struct Sender<T: Debug + Ord> {
+  shared: Arc<Shared<T>>,
+}
+
+impl<T: Debug + Ord> Sender<T> {
+  /// Sends `value` on the channel. Fails, returning `value`, if the receiver has
+  /// been dropped.
+  pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+    self.shared.queue.push(value).map_err(|e| SendError(e.0))
+  }
+}
+
+impl<T: Debug + Ord> Clone for Sender<T> {
+  fn clone(&self) -> Self {
+    self.shared.senders.fetch_add(1, Ordering::Relaxed);
+    Sender { shared: Arc::clone(&self.shared) }
+  }
+}
+
+impl<T: Debug + Ord> Drop for Sender<T> {
+  fn drop(&mut self) {
+    if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+      // Closing lets the receiver drain what is buffered and then disconnect.
+      self.shared.queue.close();
+    }
+  }
+}
+
+/// The receiving half of a priority channel.
+#[derive(Debug)]
+pub struct Receiver<T: Debug + Ord> {
+  shared: Arc<Shared<T>>,
+}
+
+impl<T: Debug + Ord> Receiver<T> {
+  /// Receives the greatest buffered message, blocking while there is none. Fails
+  /// once the channel is empty and every sender has been dropped.
+  pub fn recv(&self) -> Result<T, RecvError> {
+    self.shared.queue.pop().ok_or(RecvError)
+  }
+
+  /// Receives the greatest buffered message, if there is one, without blocking.
+  pub fn try_recv(&self) -> Result<T, TryRecvError> {
+    self.shared.queue.try_pop().map_err(|e| match e {
+      TryPopError::Empty => TryRecvError::Empty,
+      TryPopError::Closed => TryRecvError::Disconnected,
+    })
+  }
+
+  /// Receives the greatest buffered message, blocking for at most `timeout`
+  /// while there is none.
+  pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+    self.shared.queue.pop_timeout(timeout).map_err(|e| match e {
+      PopTimeoutError::Timeout => RecvTimeoutError::Timeout,
+      PopTimeoutError::Closed => RecvTimeoutError::Disconnected,
+    })
+  }
+
+  /// Returns an iterator that receives messages, blocking while there is none,
+  /// until the channel is disconnected.
+  pub fn iter(&self) -> Iter<'_, T> {
+    Iter { receiver: self }
+  }
+
+  /// Returns an iterator that receives the buffered messages without blocking.
+  pub fn try_iter(&self) -> TryIter<'_, T> {
+    TryIter { receiver: self }
+  }
+}
+
+impl<T: Debug + Ord> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    // Fails further sends, and drops the messages that can no longer be
+    // received rather than waiting for the last sender.
+    self.shared.queue.close_and_discard();
+  }
+}
+
+/// A blocking iterator over the messages of a `Receiver`, returned by
+/// `Receiver::iter`.
+#[derive(Debug)]
+pub struct Iter<'a, T: Debug + Ord> {
+  receiver: &'a Receiver<T>,
+}
+
+impl<'a, T: Debug + Ord> Iterator for Iter<'a, T> {
+  type Item = T;
+
+  fn next(&mut self) -> Option<T> {
+    self.receiver.recv().ok()
+  }
+}
+
+/// A non-blocking iterator over the buffered messages of a `Receiver`, returned
+/// by `Receiver::try_iter`.
+#[derive(Debug)]
+pub struct TryIter<'a, T: Debug + Ord> {
+  receiver: &'a Receiver<T>,
+}
+
+impl<'a, T: Debug + Ord> Iterator for TryIter<'a, T> {
+  type Item = T;
+
+  fn next(&mut self) -> Option<T> {
+    self.receiver.try_recv().ok()
+  }
+}
+
+/// An owning blocking iterator over the messages of a `Receiver`.
+#[derive(Debug)]
+pub struct IntoIter<T: Debug + Ord> {
+  receiver: Receiver<T>,
+}
+
+impl<T: Debug + Ord> Iterator for IntoIter<T> {
+  type Item = T;
+
+  fn next(&mut self) -> Option<T> {
+    self.receiver.recv().ok()
+  }
+}
+
+impl<'a, T: Debug + Ord> IntoIterator for &'a Receiver<T> {
+  type Item = T;
+  type IntoIter = Iter<'a, T>;
+
+  fn into_iter(self) -> Iter<'a, T> {
+    self.iter()
+  }
+}
+
+impl<T: Debug + Ord> IntoIterator for Receiver<T> {
+  type Item = T;
+  type IntoIter = IntoIter<T>;
+
+  fn into_iter(self) -> IntoIter<T> {
+    IntoIter { receiver: self }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+  use std::time::Duration;
+  use super::{priority_channel, RecvError, RecvTimeoutError, SendError, TryRecvError};
+  use crate::counted::{ComparisonCounts, Counted};
+
+  #[test]
+  fn receives_in_priority_order() {
+    let (tx, rx) = priority_channel();
+    for x in &[3, 1, 4, 1, 5] {
+      tx.send(*x).unwrap();
+    }
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![5, 4, 3, 1, 1]);
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(rx.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+  }
+
+  #[test]
+  fn disconnects_when_senders_are_dropped() {
+    let (tx, rx) = priority_channel();
+    let tx2 = tx.clone();
+    tx.send(1).unwrap();
+    drop(tx);
+    assert_eq!(rx.try_recv(), Ok(1));
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    tx2.send(2).unwrap();
+    drop(tx2);
+    // Buffered messages are still received after disconnection.
+    assert_eq!(rx.recv(), Ok(2));
+    assert_eq!(rx.recv(), Err(RecvError));
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    assert_eq!(rx.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Disconnected));
+  }
+
+  #[test]
+  fn send_fails_when_receiver_is_dropped() {
+    let (tx, rx) = priority_channel();
+    tx.send(1).unwrap();
+    drop(rx);
+    assert_eq!(tx.send(2), Err(SendError(2)));
+  }
+
+  #[test]
+  fn dropping_receiver_makes_no_comparisons() {
+    let (tx, rx) = priority_channel();
+    for x in 0..100 {
+      tx.send(Counted(x)).unwrap();
+    }
+    let ((), counts) = ComparisonCounts::scope(|| drop(rx));
+    assert_eq!(counts.total(), 0);
+    assert_eq!(tx.send(Counted(100)), Err(SendError(Counted(100))));
+  }
+
+  #[test]
+  fn many_senders() {
+    let (tx, rx) = priority_channel();
+    let senders: Vec<_> = (0..4).map(|s| {
+      let tx = tx.clone();
+      thread::spawn(move || {
+        for i in 0..1000 {
+          tx.send(s * 1000 + i).unwrap();
+        }
+      })
+    }).collect();
+    drop(tx);
+    let blocked = thread::spawn(move || {
+      let mut received: Vec<i32> = rx.into_iter().collect();
+      received.sort_unstable();
+      received
+    });
+    for sender in senders {
+      sender.join().unwrap();
+    }
+    assert_eq!(blocked.join().unwrap(), (0..4000).collect::<Vec<_>>());
+  }
+}
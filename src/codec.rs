@@ -0,0 +1,125 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fixed-size binary encodings of heap elements, used by snapshots and by
+//! heaps that store elements outside of memory.
+
+use std::io;
+
+/// A type whose values can be encoded in exactly `SIZE` bytes.
+///
+/// Implementations are provided for the primitive integer types, which are
+/// encoded little-endian, and for byte arrays.
+///
+/// ```rust
+/// # use std::io;
+/// # use weak_heap::codec::Codec;
+/// # fn main() {
+/// #[derive(Debug, PartialEq)]
+/// struct Job { priority: u32, id: u64 }
+///
+/// impl Codec for Job {
+///   const SIZE: usize = 12;
+///
+///   fn encode(&self, buf: &mut [u8]) {
+///     self.priority.encode(&mut buf[..4]);
+///     self.id.encode(&mut buf[4..]);
+///   }
+///
+///   fn decode(buf: &[u8]) -> io::Result<Self> {
+///     Ok(Job { priority: u32::decode(&buf[..4])?, id: u64::decode(&buf[4..])? })
+///   }
+/// }
+///
+/// let mut buf = [0; Job::SIZE];
+/// Job { priority: 3, id: 7 }.encode(&mut buf);
+/// assert_eq!(Job::decode(&buf).unwrap(), Job { priority: 3, id: 7 });
+/// # }
+/// ```
+pub trait Codec: Sized {
+  /// The number of bytes in the encoding of every value.
+  const SIZE: usize;
+
+  /// Encodes `self` into `buf`, which is exactly `SIZE` bytes long.
+  fn encode(&self, buf: &mut [u8]);
+
+  /// Decodes a value from `buf`, which is exactly `SIZE` bytes long. Returns an
+  /// error of kind `InvalidData` if `buf` does not hold a valid encoding.
+  fn decode(buf: &[u8]) -> io::Result<Self>;
+}
+
+macro_rules! int_codec {
+  ($($t:ty),*) => {
+    $(
+      impl Codec for $t {
+        const SIZE: usize = std::mem::size_of::<$t>();
+
+        fn encode(&self, buf: &mut [u8]) {
+          buf.copy_from_slice(&self.to_le_bytes());
+        }
+
+        fn decode(buf: &[u8]) -> io::Result<Self> {
+          let mut bytes = [0; std::mem::size_of::<$t>()];
+          bytes.copy_from_slice(buf);
+          Ok(<$t>::from_le_bytes(bytes))
+        }
+      }
+    )*
+  };
+}
+
+int_codec!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl<const N: usize> Codec for [u8; N] {
+  const SIZE: usize = N;
+
+  fn encode(&self, buf: &mut [u8]) {
+    buf.copy_from_slice(self);
+  }
+
+  fn decode(buf: &[u8]) -> io::Result<Self> {
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(buf);
+    Ok(bytes)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Codec;
+
+  fn round_trip<T: Codec + Copy + PartialEq + std::fmt::Debug>(value: T) {
+    let mut buf = vec![0; T::SIZE];
+    value.encode(&mut buf);
+    assert_eq!(T::decode(&buf).unwrap(), value);
+  }
+
+  #[test]
+  fn integers() {
+    round_trip(0xabu8);
+    round_trip(-2i16);
+    round_trip(u32::MAX);
+    round_trip(i64::MIN);
+    round_trip(-1i128);
+    let mut buf = [0; 4];
+    0x0102_0304u32.encode(&mut buf);
+    assert_eq!(buf, [4, 3, 2, 1]);
+  }
+
+  #[test]
+  fn byte_arrays() {
+    round_trip(*b"weak heap");
+    round_trip([0u8; 0]);
+  }
+}
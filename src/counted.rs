@@ -0,0 +1,215 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A wrapper type that counts the comparisons made between its values.
+//!
+//! Counts are kept per thread, so concurrently running tests or benchmarks do
+//! not disturb each other's measurements:
+//!
+//! ```rust
+//! # use weak_heap::WeakHeap;
+//! # use weak_heap::counted::{ComparisonCounts, Counted};
+//! # fn main() {
+//! let (sorted, counts) = ComparisonCounts::scope(|| {
+//!   let heap: WeakHeap<Counted<i32>> = (0..100).map(Counted).collect();
+//!   heap.into_sorted_vec()
+//! });
+//! assert_eq!(sorted.len(), 100);
+//! assert!(counts.total() > 0);
+//! # }
+//! ```
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::ops::Add;
+
+thread_local! {
+  static COUNTS: Cell<ComparisonCounts> = Cell::new(ComparisonCounts::default());
+}
+
+/// Applies `f` to this thread's comparison counts.
+fn record<F: FnOnce(&mut ComparisonCounts)>(f: F) {
+  COUNTS.with(|counts| {
+    let mut c = counts.get();
+    f(&mut c);
+    counts.set(c);
+  });
+}
+
+/// Wraps a value of type `T`, recording each comparison between two wrapped
+/// values in the current thread's `ComparisonCounts`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Counted<T>(pub T);
+
+impl<T> Counted<T> {
+  /// Returns the wrapped value.
+  pub fn into_inner(self) -> T {
+    self.0
+  }
+}
+
+impl<T> From<T> for Counted<T> {
+  fn from(x: T) -> Self {
+    Counted(x)
+  }
+}
+
+// `partial_cmp` and `ne` are implemented by hand so that each is counted
+// separately, rather than being derived from `cmp` and `eq`.
+#[allow(clippy::non_canonical_partial_ord_impl)]
+impl<T: PartialOrd> PartialOrd for Counted<T> {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    record(|c| c.partial_ord += 1);
+    self.0.partial_cmp(&other.0)
+  }
+}
+
+impl<T: Ord> Ord for Counted<T> {
+  fn cmp(&self, other: &Self) -> Ordering {
+    record(|c| c.ord += 1);
+    self.0.cmp(&other.0)
+  }
+}
+
+#[allow(clippy::partialeq_ne_impl)]
+impl<T: PartialEq> PartialEq for Counted<T> {
+  fn eq(&self, other: &Self) -> bool {
+    record(|c| c.eq += 1);
+    self.0.eq(&other.0)
+  }
+
+  fn ne(&self, other: &Self) -> bool {
+    record(|c| c.neq += 1);
+    self.0.ne(&other.0)
+  }
+}
+
+impl<T: Eq> Eq for Counted<T> {}
+
+/// The number of comparisons of each kind made between `Counted` values.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, PartialOrd, Ord)]
+pub struct ComparisonCounts {
+  /// Calls to `PartialOrd::partial_cmp`.
+  pub partial_ord: usize,
+  /// Calls to `Ord::cmp`.
+  pub ord: usize,
+  /// Calls to `PartialEq::eq`.
+  pub eq: usize,
+  /// Calls to `PartialEq::ne`.
+  pub neq: usize,
+}
+
+impl ComparisonCounts {
+  /// Returns the number of comparisons made on this thread so far.
+  pub fn now() -> Self {
+    COUNTS.with(|counts| counts.get())
+  }
+
+  /// Calls `f`, returning its result along with the number of comparisons it
+  /// made on this thread. Scopes may be nested.
+  pub fn scope<R, F: FnOnce() -> R>(f: F) -> (R, ComparisonCounts) {
+    let mut counts = ComparisonCounts::now();
+    let result = f();
+    counts.take_difference();
+    (result, counts)
+  }
+
+  /// Replaces `self`, which was returned by an earlier call to `now`, with the
+  /// number of comparisons made on this thread since then.
+  pub fn take_difference(&mut self) {
+    let now = ComparisonCounts::now();
+    let since = |now: usize, then: usize| now.checked_sub(then).expect("counts were not taken earlier on this thread");
+    *self = ComparisonCounts {
+      partial_ord: since(now.partial_ord, self.partial_ord),
+      ord: since(now.ord, self.ord),
+      eq: since(now.eq, self.eq),
+      neq: since(now.neq, self.neq),
+    };
+  }
+
+  /// Returns the number of comparisons of all kinds.
+  pub fn total(&self) -> usize {
+    self.partial_ord + self.ord + self.eq + self.neq
+  }
+}
+
+impl Add for ComparisonCounts {
+  type Output = Self;
+
+  fn add(self, rhs: Self) -> Self {
+    ComparisonCounts {
+      partial_ord: self.partial_ord + rhs.partial_ord,
+      ord: self.ord + rhs.ord,
+      eq: self.eq + rhs.eq,
+      neq: self.neq + rhs.neq,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{ComparisonCounts, Counted};
+  use std::thread;
+
+  #[test]
+  fn counts_each_kind() {
+    let (_, counts) = ComparisonCounts::scope(|| {
+      let (a, b) = (Counted(1), Counted(2));
+      let _ = a < b;
+      let _ = a.cmp(&b);
+      let _ = a == b;
+      let _ = a != b;
+      let _ = a != b;
+    });
+    assert_eq!(counts, ComparisonCounts { partial_ord: 1, ord: 1, eq: 1, neq: 2, });
+    assert_eq!(counts.total(), 5);
+  }
+
+  #[test]
+  fn nested_scopes() {
+    let (inner, outer) = ComparisonCounts::scope(|| {
+      let _ = Counted(1) < Counted(2);
+      ComparisonCounts::scope(|| Counted(1).cmp(&Counted(2))).1
+    });
+    assert_eq!(inner, ComparisonCounts { ord: 1, ..ComparisonCounts::default() });
+    assert_eq!(outer, ComparisonCounts { partial_ord: 1, ord: 1, ..ComparisonCounts::default() });
+  }
+
+  #[test]
+  #[should_panic(expected = "counts were not taken earlier")]
+  fn difference_checks_every_counter() {
+    let _ = Counted(1) < Counted(2);
+    let mut counts = ComparisonCounts::now();
+    // Lexicographically earlier than now, but with a count from the future.
+    counts.partial_ord -= 1;
+    counts.ord += 1;
+    counts.take_difference();
+  }
+
+  #[test]
+  fn threads_are_isolated() {
+    let (_, counts) = ComparisonCounts::scope(|| {
+      let handles: Vec<_> = (0..4).map(|_| thread::spawn(|| {
+        for i in 0..1000 {
+          let _ = Counted(i) < Counted(0);
+        }
+      })).collect();
+      for handle in handles {
+        handle.join().unwrap();
+      }
+      let _ = Counted(1) < Counted(2);
+    });
+    assert_eq!(counts.partial_ord, 1);
+  }
+}
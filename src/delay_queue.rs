@@ -0,0 +1,449 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A queue of items that become available once their deadlines pass.
+//!
+//! `DelayQueue` orders its items by deadline in a `WeakHeap`. Inserting an item
+//! returns a `Key` with which its deadline can later be reset or the item
+//! removed. A weak heap cannot change the priority of an element in place, so
+//! resetting or removing an item leaves its old entry in the heap to be skipped
+//! when it reaches the top; the heap is rebuilt without such entries once they
+//! outnumber the items.
+//!
+//! Time is read from a `Clock`. `SystemClock` is the real one, and
+//! `ManualClock` is one that only moves when told to, for tests. Waiting on a
+//! `ManualClock` advances it to the end of the wait instead of sleeping.
+//!
+//! ```rust
+//! # use std::time::Duration;
+//! # use weak_heap::delay_queue::{DelayQueue, ManualClock};
+//! # fn main() {
+//! let clock = ManualClock::new();
+//! let mut queue = DelayQueue::with_clock(clock.clone());
+//! let start = clock.now();
+//! let a = queue.insert_after("a", Duration::from_secs(5));
+//! queue.insert_after("b", Duration::from_secs(2));
+//! queue.reset(a, start + Duration::from_secs(1));
+//! assert_eq!(queue.poll_expired(), None);
+//!
+//! // Returns at once, having moved the clock forward a second.
+//! let expired = queue.next_expired().unwrap();
+//! assert_eq!((expired.value, clock.now() - start), ("a", Duration::from_secs(1)));
+//! assert_eq!(queue.next_expired().unwrap().value, "b");
+//! # }
+//! ```
+
+use std::cmp::Reverse;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::WeakHeap;
+
+/// The delay that `DelayQueue::insert_after` falls back to when a deadline
+/// cannot be represented.
+const FAR_FUTURE: Duration = Duration::from_secs(30 * 365 * 24 * 60 * 60);
+
+/// Returns the instant `delay` after `now`, or as close to `FAR_FUTURE` after it
+/// as can be represented if that cannot.
+fn deadline_after(now: Instant, mut delay: Duration) -> Instant {
+  loop {
+    if let Some(deadline) = now.checked_add(delay) {
+      return deadline;
+    }
+    delay = (delay / 2).min(FAR_FUTURE);
+  }
+}
+
+/// A source of the current time, which can wait for a time to arrive.
+pub trait Clock {
+  /// Returns the current time.
+  fn now(&self) -> Instant;
+
+  /// Blocks until `now()` is at least `deadline`.
+  fn sleep_until(&self, deadline: Instant);
+}
+
+/// The system's monotonic clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+
+  fn sleep_until(&self, deadline: Instant) {
+    // `thread::sleep` may wake early, so check again.
+    loop {
+      let now = Instant::now();
+      if now >= deadline {
+        return;
+      }
+      thread::sleep(deadline - now);
+    }
+  }
+}
+
+/// A clock that moves only when advanced, for deterministic tests. Clones share
+/// the same time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+  now: Arc<Mutex<Instant>>,
+}
+
+impl Default for ManualClock {
+  fn default() -> Self {
+    ManualClock::new()
+  }
+}
+
+impl ManualClock {
+  /// Creates a clock that starts at the current system time.
+  pub fn new() -> Self {
+    ManualClock { now: Arc::new(Mutex::new(Instant::now())) }
+  }
+
+  /// Returns the clock's current time.
+  pub fn now(&self) -> Instant {
+    *self.now.lock().unwrap()
+  }
+
+  /// Moves the clock forward by `duration`.
+  pub fn advance(&self, duration: Duration) {
+    *self.now.lock().unwrap() += duration;
+  }
+}
+
+impl Clock for ManualClock {
+  fn now(&self) -> Instant {
+    ManualClock::now(self)
+  }
+
+  /// Moves the clock forward to `deadline`, if it is not there already, rather
+  /// than waiting.
+  fn sleep_until(&self, deadline: Instant) {
+    let mut now = self.now.lock().unwrap();
+    *now = (*now).max(deadline);
+  }
+}
+
+/// Identifies an item inserted into a `DelayQueue`. A key is valid until its
+/// item expires or is removed; after that, using it does nothing.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Key {
+  index: usize,
+  generation: u64,
+}
+
+/// An item whose deadline has passed, returned by `DelayQueue::poll_expired`
+/// and `DelayQueue::next_expired`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Expired<T> {
+  pub value: T,
+  pub deadline: Instant,
+  pub key: Key,
+}
+
+#[derive(Debug)]
+struct Item<T> {
+  value: T,
+  deadline: Instant,
+  /// The sequence number of the item's current heap entry.
+  seq: u64,
+}
+
+#[derive(Debug)]
+struct Slot<T> {
+  /// The sequence number of the insertion that last filled the slot, which
+  /// distinguishes its keys from those of earlier occupants.
+  generation: u64,
+  item: Option<Item<T>>,
+}
+
+/// A heap entry: a deadline, the sequence number that breaks ties in insertion
+/// order and identifies the entry, and the index of the item's slot.
+type Entry = Reverse<(Instant, u64, usize)>;
+
+/// A queue of items that each become available once their deadline passes,
+/// earliest deadline first, and in insertion order among equal deadlines.
+///
+/// It does not implement `PriorityQueue`; see that trait for why.
+#[derive(Debug)]
+pub struct DelayQueue<T, C: Clock = SystemClock> {
+  heap: WeakHeap<Entry>,
+  slots: Vec<Slot<T>>,
+  free: Vec<usize>,
+  len: usize,
+  next_seq: u64,
+  clock: C,
+}
+
+impl<T> Default for DelayQueue<T> {
+  fn default() -> Self {
+    DelayQueue::new()
+  }
+}
+
+impl<T> DelayQueue<T> {
+  /// Creates an empty queue that uses the system clock.
+  pub fn new() -> Self {
+    DelayQueue::with_clock(SystemClock)
+  }
+}
+
+impl<T, C: Clock> DelayQueue<T, C> {
+  /// Creates an empty queue that reads the time from `clock`.
+  pub fn with_clock(clock: C) -> Self {
+    DelayQueue { heap: WeakHeap::new(), slots: Vec::new(), free: Vec::new(), len: 0, next_seq: 0, clock, }
+  }
+
+  /// Returns the queue's clock.
+  pub fn clock(&self) -> &C {
+    &self.clock
+  }
+
+  /// Returns the number of items in the queue, expired or not.
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  /// Returns `true` iff the queue holds no items.
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  fn next_seq(&mut self) -> u64 {
+    let seq = self.next_seq;
+    self.next_seq += 1;
+    seq
+  }
+
+  /// Inserts `value`, to become available at `deadline`, and returns its key.
+  pub fn insert_at(&mut self, value: T, deadline: Instant) -> Key {
+    let seq = self.next_seq();
+    let item = Some(Item { value, deadline, seq });
+    let index = match self.free.pop() {
+      Some(index) => {
+        self.slots[index] = Slot { generation: seq, item };
+        index
+      },
+      None => {
+        self.slots.push(Slot { generation: seq, item });
+        self.slots.len() - 1
+      },
+    };
+    self.heap.push(Reverse((deadline, seq, index)));
+    self.len += 1;
+    Key { index, generation: seq }
+  }
+
+  /// Inserts `value`, to become available once `delay` has passed, and returns
+  /// its key. A `delay` so long that the deadline cannot be represented, such
+  /// as `Duration::MAX`, is shortened to about thirty years, which in practice
+  /// never passes.
+  pub fn insert_after(&mut self, value: T, delay: Duration) -> Key {
+    let deadline = deadline_after(self.clock.now(), delay);
+    self.insert_at(value, deadline)
+  }
+
+  fn item(&self, key: Key) -> Option<&Item<T>> {
+    self.slots.get(key.index).filter(|slot| slot.generation == key.generation)?.item.as_ref()
+  }
+
+  /// Returns the deadline of the item with `key`, if it is still in the queue.
+  pub fn deadline(&self, key: Key) -> Option<Instant> {
+    self.item(key).map(|item| item.deadline)
+  }
+
+  /// Changes the deadline of the item with `key` to `deadline`. Returns `false`
+  /// if the item is no longer in the queue.
+  pub fn reset(&mut self, key: Key, deadline: Instant) -> bool {
+    if self.item(key).is_none() {
+      return false;
+    }
+    let seq = self.next_seq();
+    let item = self.slots[key.index].item.as_mut().unwrap();
+    item.deadline = deadline;
+    item.seq = seq;
+    self.heap.push(Reverse((deadline, seq, key.index)));
+    self.compact_if_stale();
+    true
+  }
+
+  /// Removes the item with `key` from the queue and returns its value, if it is
+  /// still in the queue.
+  pub fn remove(&mut self, key: Key) -> Option<T> {
+    self.item(key)?;
+    let value = self.take(key.index);
+    self.compact_if_stale();
+    Some(value)
+  }
+
+  /// Empties the slot at `index`, which must hold an item, and returns its
+  /// value.
+  fn take(&mut self, index: usize) -> T {
+    let item = self.slots[index].item.take().unwrap();
+    self.free.push(index);
+    self.len -= 1;
+    item.value
+  }
+
+  /// Rebuilds the heap from the items once stale entries outnumber them, so
+  /// that the heap's size stays proportional to the queue's.
+  fn compact_if_stale(&mut self) {
+    if self.heap.len() > 2 * self.len + 32 {
+      let entries: Vec<Entry> = self.slots.iter().enumerate()
+        .filter_map(|(index, slot)| slot.item.as_ref().map(|item| Reverse((item.deadline, item.seq, index))))
+        .collect();
+      self.heap = WeakHeap::from_vec(entries);
+    }
+  }
+
+  /// Discards stale entries from the top of the heap and returns the earliest
+  /// live one.
+  fn top(&mut self) -> Option<(Instant, usize)> {
+    while let Some(&Reverse((deadline, seq, index))) = self.heap.peek() {
+      if self.slots[index].item.as_ref().is_some_and(|item| item.seq == seq) {
+        return Some((deadline, index));
+      }
+      self.heap.pop();
+    }
+    None
+  }
+
+  /// Returns the earliest deadline of the items in the queue.
+  pub fn next_deadline(&mut self) -> Option<Instant> {
+    self.top().map(|(deadline, _)| deadline)
+  }
+
+  /// Removes and returns the item with the earliest deadline if that deadline
+  /// has passed, without blocking.
+  pub fn poll_expired(&mut self) -> Option<Expired<T>> {
+    let (deadline, index) = self.top()?;
+    if deadline > self.clock.now() {
+      return None;
+    }
+    Some(self.expire(deadline, index))
+  }
+
+  /// Removes and returns the item with the earliest deadline, first blocking
+  /// until that deadline has passed. Returns `None` at once if the queue is
+  /// empty.
+  pub fn next_expired(&mut self) -> Option<Expired<T>> {
+    let (deadline, index) = self.top()?;
+    self.clock.sleep_until(deadline);
+    Some(self.expire(deadline, index))
+  }
+
+  fn expire(&mut self, deadline: Instant, index: usize) -> Expired<T> {
+    self.heap.pop();
+    let key = Key { index, generation: self.slots[index].generation };
+    Expired { value: self.take(index), deadline, key }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+  use super::{DelayQueue, ManualClock};
+
+  fn secs(n: u64) -> Duration {
+    Duration::from_secs(n)
+  }
+
+  #[test]
+  fn expires_in_deadline_order() {
+    let clock = ManualClock::new();
+    let mut queue = DelayQueue::with_clock(clock.clone());
+    for (value, delay) in &[("c", 3), ("a", 1), ("b2", 2), ("d", 4), ("b1", 2)] {
+      queue.insert_after(*value, secs(*delay));
+    }
+    assert_eq!(queue.poll_expired(), None);
+    clock.advance(secs(2));
+    let expired: Vec<&str> = std::iter::from_fn(|| queue.poll_expired()).map(|e| e.value).collect();
+    // Equal deadlines expire in insertion order.
+    assert_eq!(expired, vec!["a", "b2", "b1"]);
+    let start = clock.now();
+    assert_eq!(queue.next_expired().map(|e| e.value), Some("c"));
+    assert_eq!(queue.next_expired().map(|e| e.value), Some("d"));
+    assert_eq!(clock.now() - start, secs(2));
+    assert_eq!(queue.next_expired(), None);
+    assert!(queue.is_empty());
+  }
+
+  #[test]
+  fn unrepresentable_delay_saturates() {
+    let clock = ManualClock::new();
+    let mut queue = DelayQueue::with_clock(clock.clone());
+    let forever = queue.insert_after("forever", Duration::MAX);
+    queue.insert_after("soon", secs(1));
+    assert!(queue.deadline(forever).unwrap() >= clock.now() + secs(365 * 24 * 60 * 60));
+    assert_eq!(queue.next_expired().map(|e| e.value), Some("soon"));
+    clock.advance(secs(365 * 24 * 60 * 60));
+    assert_eq!(queue.poll_expired(), None);
+  }
+
+  #[test]
+  fn reset_and_remove() {
+    let clock = ManualClock::new();
+    let start = clock.now();
+    let mut queue = DelayQueue::with_clock(clock.clone());
+    let a = queue.insert_at('a', start + secs(1));
+    let b = queue.insert_at('b', start + secs(2));
+    let c = queue.insert_at('c', start + secs(3));
+    assert!(queue.reset(a, start + secs(10)));
+    assert_eq!(queue.deadline(a), Some(start + secs(10)));
+    assert_eq!(queue.remove(b), Some('b'));
+    assert_eq!(queue.remove(b), None);
+    assert!(!queue.reset(b, start));
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.next_deadline(), Some(start + secs(3)));
+
+    let expired = queue.next_expired().unwrap();
+    assert_eq!((expired.value, expired.key), ('c', c));
+    assert_eq!(queue.remove(c), None);
+    // A new item reusing the slot of `b` is not reached through `b`.
+    let d = queue.insert_at('d', start + secs(5));
+    assert_eq!(queue.deadline(b), None);
+    assert_eq!(queue.next_expired().map(|e| (e.value, e.key)), Some(('d', d)));
+    assert_eq!(queue.next_expired().map(|e| (e.value, e.deadline)), Some(('a', start + secs(10))));
+  }
+
+  #[test]
+  fn stale_entries_are_compacted() {
+    let clock = ManualClock::new();
+    let start = clock.now();
+    let mut queue = DelayQueue::with_clock(clock);
+    let keys: Vec<_> = (0..100u64).map(|i| queue.insert_at(i, start + secs(i))).collect();
+    for round in 1..=50 {
+      for (i, key) in keys.iter().enumerate() {
+        assert!(queue.reset(*key, start + secs(1000 * round - i as u64)));
+      }
+    }
+    assert!(queue.heap.len() <= 2 * queue.len() + 32);
+    let order: Vec<u64> = std::iter::from_fn(|| queue.next_expired()).map(|e| e.value).collect();
+    assert_eq!(order, (0..100).rev().collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn system_clock_blocks_until_deadline() {
+    let mut queue = DelayQueue::new();
+    let start = Instant::now();
+    queue.insert_after(1, Duration::from_millis(20));
+    assert_eq!(queue.poll_expired(), None);
+    assert_eq!(queue.next_expired().map(|e| e.value), Some(1));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+  }
+}
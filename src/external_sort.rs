@@ -0,0 +1,330 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sorting lines of text that do not fit in memory, as done by the
+//! `weak-heap-sort` tool.
+//!
+//! Sorted runs are generated by replacement selection: a `WeakHeap` is filled
+//! up to the memory limit, and then its least line is repeatedly written to
+//! the current run and replaced by the next line of input. A line that sorts
+//! before the one just written is held back for the next run. On random input
+//! this produces runs about twice as large as the memory limit, and already
+//! sorted input produces a single run.
+//!
+//! The runs are written to temporary files, and then merged `fan_in` at a time
+//! by a k-way merge, itself driven by a `WeakHeap`, until one run remains.
+//!
+//! ```rust
+//! # use weak_heap::external_sort::{sort, SortConfig};
+//! # fn main() {
+//! let mut output = Vec::new();
+//! sort(&b"pear\napple\nfig\n"[..], &mut output, &SortConfig::default()).unwrap();
+//! assert_eq!(output, b"apple\nfig\npear\n");
+//! # }
+//! ```
+
+use std::cmp::Reverse;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::WeakHeap;
+use crate::keys::{KeySpec, KeyedLine};
+
+/// Configuration for `sort`.
+#[derive(Clone, Debug)]
+pub struct SortConfig {
+  /// How lines are ordered.
+  pub key: KeySpec,
+  /// The approximate number of bytes of lines to hold in memory at once while
+  /// generating runs.
+  pub memory_limit: usize,
+  /// The number of runs merged at once. Runs are merged in several passes if
+  /// there are more than this. Must be at least 2.
+  pub fan_in: usize,
+  /// The directory in which temporary run files are created.
+  pub temp_dir: PathBuf,
+}
+
+impl Default for SortConfig {
+  /// Sorts whole lines lexically with 64 MiB of memory, merging up to 64 runs
+  /// at once in the system temporary directory.
+  fn default() -> Self {
+    SortConfig {
+      key: KeySpec::default(),
+      memory_limit: 64 << 20,
+      fan_in: 64,
+      temp_dir: env::temp_dir(),
+    }
+  }
+}
+
+/// A summary of the work done by `sort`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SortStats {
+  /// The number of lines sorted.
+  pub lines: u64,
+  /// The number of runs generated by replacement selection.
+  pub runs: usize,
+  /// The largest number of lines held in the heap during run generation.
+  pub heap_lines: usize,
+  /// The number of merge passes made, each of which reads and writes every
+  /// line once.
+  pub merge_passes: usize,
+}
+
+/// The approximate memory used by a line of `len` bytes held in the heap.
+fn line_cost(len: usize) -> usize {
+  len + mem::size_of::<(usize, KeyedLine)>() + 1
+}
+
+/// Reads the next line from `input`, without its line terminator.
+fn read_line<R: BufRead>(input: &mut R) -> io::Result<Option<Vec<u8>>> {
+  let mut line = Vec::new();
+  if input.read_until(b'\n', &mut line)? == 0 {
+    return Ok(None);
+  }
+  if line.last() == Some(&b'\n') {
+    line.pop();
+  }
+  Ok(Some(line))
+}
+
+/// A temporary file holding a sorted run, deleted when dropped.
+struct Run {
+  path: PathBuf,
+}
+
+impl Run {
+  /// Creates a new, empty run file in `dir`.
+  fn create(dir: &Path) -> io::Result<(Run, BufWriter<File>)> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    loop {
+      let n = NEXT.fetch_add(1, Ordering::Relaxed);
+      let path = dir.join(format!("weak-heap-sort-{}-{}.run", process::id(), n));
+      match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(file) => return Ok((Run { path, }, BufWriter::new(file))),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  fn open(&self) -> io::Result<BufReader<File>> {
+    File::open(&self.path).map(BufReader::new)
+  }
+}
+
+impl Drop for Run {
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.path);
+  }
+}
+
+/// Sorts the lines of `input` and writes them to `output`, each terminated by
+/// a newline. Lines with equal keys may be written in any order.
+pub fn sort<R: BufRead, W: Write>(mut input: R, output: &mut W, config: &SortConfig)
+                                  -> io::Result<SortStats> {
+  assert!(config.fan_in >= 2, "fan_in must be at least 2");
+  let mut stats = SortStats::default();
+  let mut runs = generate_runs(&mut input, config, &mut stats)?;
+  while runs.len() > config.fan_in {
+    stats.merge_passes += 1;
+    let mut merged = Vec::new();
+    while !runs.is_empty() {
+      let group: Vec<Run> = runs.drain(..config.fan_in.min(runs.len())).collect();
+      let (run, mut writer) = Run::create(&config.temp_dir)?;
+      merge(&group, &mut writer, &config.key)?;
+      writer.flush()?;
+      merged.push(run);
+    }
+    runs = merged;
+  }
+  stats.merge_passes += 1;
+  merge(&runs, output, &config.key)?;
+  output.flush()?;
+  Ok(stats)
+}
+
+/// Splits `input` into sorted runs by replacement selection.
+fn generate_runs<R: BufRead>(input: &mut R, config: &SortConfig, stats: &mut SortStats)
+                             -> io::Result<Vec<Run>> {
+  // Lines are ordered first by run and then by key. `WeakHeap` is a max-heap,
+  // so the order is reversed to pop the least line of the earliest run.
+  let mut heap: WeakHeap<Reverse<(usize, KeyedLine)>> = WeakHeap::new();
+  let mut memory = 0;
+  while memory < config.memory_limit || heap.is_empty() {
+    match read_line(input)? {
+      Some(line) => {
+        memory += line_cost(line.len());
+        heap.push(Reverse((0, config.key.keyed(line))));
+        stats.lines += 1;
+      },
+      None => break,
+    }
+  }
+  stats.heap_lines = heap.len();
+
+  let mut runs = Vec::new();
+  let mut writer: Option<BufWriter<File>> = None;
+  while let Some(Reverse((run, line))) = heap.pop() {
+    memory -= line_cost(line.line().len());
+    if run == runs.len() {
+      if let Some(mut w) = writer.take() {
+        w.flush()?;
+      }
+      let (file, w) = Run::create(&config.temp_dir)?;
+      runs.push(file);
+      writer = Some(w);
+    }
+    let w = writer.as_mut().unwrap();
+    w.write_all(line.line())?;
+    w.write_all(b"\n")?;
+    while memory < config.memory_limit || heap.is_empty() {
+      let next = match read_line(input)? {
+        Some(next) => config.key.keyed(next),
+        None => break,
+      };
+      stats.lines += 1;
+      memory += line_cost(next.line().len());
+      let next_run = if next < line { run + 1 } else { run };
+      heap.push(Reverse((next_run, next)));
+      stats.heap_lines = stats.heap_lines.max(heap.len());
+    }
+  }
+  if let Some(mut w) = writer {
+    w.flush()?;
+  }
+  stats.runs = runs.len();
+  Ok(runs)
+}
+
+/// Merges the sorted `runs` into `output`.
+fn merge<W: Write>(runs: &[Run], output: &mut W, key: &KeySpec) -> io::Result<()> {
+  let mut readers = runs.iter().map(Run::open).collect::<io::Result<Vec<_>>>()?;
+  // Each entry records the run it came from, so that run can be refilled.
+  let mut heap: WeakHeap<Reverse<(KeyedLine, usize)>> = WeakHeap::with_capacity(runs.len());
+  for (i, reader) in readers.iter_mut().enumerate() {
+    if let Some(line) = read_line(reader)? {
+      heap.push(Reverse((key.keyed(line), i)));
+    }
+  }
+  while let Some(Reverse((line, i))) = heap.pop() {
+    output.write_all(line.line())?;
+    output.write_all(b"\n")?;
+    if let Some(next) = read_line(&mut readers[i])? {
+      heap.push(Reverse((key.keyed(next), i)));
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs::{self, File};
+  use std::io::{BufReader, Read, Write};
+  use rand::{Rng, SeedableRng};
+  use rand::rngs::StdRng;
+  use super::{sort, SortConfig};
+  use crate::keys::{Comparison, KeySpec};
+
+  fn random_lines(n: usize) -> Vec<String> {
+    let mut rng = StdRng::seed_from_u64(44);
+    (0..n).map(|i| format!("{:08} line {}", rng.gen_range(0, 100_000_000), i)).collect()
+  }
+
+  fn run(lines: &[String], config: &SortConfig) -> (Vec<String>, super::SortStats) {
+    let input = lines.join("\n");
+    let mut output = Vec::new();
+    let stats = sort(input.as_bytes(), &mut output, config).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    (output.lines().map(String::from).collect(), stats)
+  }
+
+  #[test]
+  fn replacement_selection_doubles_runs() {
+    let lines = random_lines(20_000);
+    let config = SortConfig { memory_limit: 20_000, ..SortConfig::default() };
+    let (sorted, stats) = run(&lines, &config);
+    let mut expected = lines.clone();
+    expected.sort();
+    assert_eq!(sorted, expected);
+    assert_eq!(stats.lines, 20_000);
+    let average = stats.lines as f64 / stats.runs as f64;
+    assert!(average > 1.7 * stats.heap_lines as f64,
+            "{} runs averaging {} lines from a heap of {}", stats.runs, average, stats.heap_lines);
+  }
+
+  #[test]
+  fn sorted_input_is_one_run() {
+    let mut lines = random_lines(5_000);
+    lines.sort();
+    let config = SortConfig { memory_limit: 1_000, ..SortConfig::default() };
+    let (sorted, stats) = run(&lines, &config);
+    assert_eq!(sorted, lines);
+    assert_eq!(stats.runs, 1);
+  }
+
+  #[test]
+  fn multiple_merge_passes() {
+    let lines = random_lines(3_000);
+    let config = SortConfig { memory_limit: 2_000, fan_in: 3, ..SortConfig::default() };
+    let (sorted, stats) = run(&lines, &config);
+    let mut expected = lines.clone();
+    expected.sort();
+    assert_eq!(sorted, expected);
+    assert!(stats.merge_passes > 2, "{:?}", stats);
+  }
+
+  #[test]
+  fn numeric_reverse_field() {
+    let lines: Vec<String> = vec!["b 10", "a 9", "c 100", "d x", "e -3"].into_iter().map(String::from).collect();
+    let key = KeySpec { field: Some(2), comparison: Comparison::Numeric, reverse: true, ..KeySpec::default() };
+    let config = SortConfig { key, memory_limit: 0, ..SortConfig::default() };
+    let (sorted, _) = run(&lines, &config);
+    assert_eq!(sorted, vec!["c 100", "b 10", "a 9", "e -3", "d x"]);
+  }
+
+  #[test]
+  fn empty_input() {
+    let (sorted, stats) = run(&[], &SortConfig::default());
+    assert!(sorted.is_empty());
+    assert_eq!(stats.runs, 0);
+  }
+
+  #[test]
+  fn local_files() {
+    let dir = env::temp_dir().join(format!("weak-heap-sort-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let input_path = dir.join("input.txt");
+    let output_path = dir.join("output.txt");
+    let lines = random_lines(1_000);
+    File::create(&input_path).unwrap().write_all(lines.join("\n").as_bytes()).unwrap();
+    let config = SortConfig { memory_limit: 4_000, temp_dir: dir.clone(), ..SortConfig::default() };
+    let input = BufReader::new(File::open(&input_path).unwrap());
+    sort(input, &mut File::create(&output_path).unwrap(), &config).unwrap();
+    let mut output = String::new();
+    File::open(&output_path).unwrap().read_to_string(&mut output).unwrap();
+    let mut expected = lines.clone();
+    expected.sort();
+    assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+    // Only the input and output remain; every run file has been removed.
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
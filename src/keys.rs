@@ -0,0 +1,232 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ordering lines of text by a key, as used by the `weak-heap-sort` and
+//! `weak-heap-top` tools.
+//!
+//! ```rust
+//! # use weak_heap::keys::{Comparison, KeySpec};
+//! # fn main() {
+//! let spec = KeySpec { field: Some(2), comparison: Comparison::Numeric, ..KeySpec::default() };
+//! let a = spec.keyed(b"x 10".to_vec());
+//! let b = spec.keyed(b"y 9".to_vec());
+//! assert!(b < a);
+//! # }
+//! ```
+
+use std::cmp::Ordering;
+use std::ops::Range;
+use std::str;
+#[cfg(feature = "regex")]
+use regex::bytes::Regex;
+
+/// How keys are compared.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Comparison {
+  /// Keys are compared byte by byte.
+  Lexical,
+  /// Keys are parsed as floating-point numbers, ignoring surrounding
+  /// whitespace. Keys that do not parse order before all numbers.
+  Numeric,
+}
+
+/// Describes which part of a line is its key, and how keys are ordered.
+#[derive(Clone, Debug)]
+pub struct KeySpec {
+  /// The 1-based index of the field to use as the key, or `None` to use the
+  /// whole line. A line with too few fields has an empty key.
+  pub field: Option<usize>,
+  /// The byte that separates fields, or `None` to split fields on runs of
+  /// ASCII whitespace.
+  pub separator: Option<u8>,
+  /// A regular expression to search for within the field, or the whole line
+  /// if `field` is `None`. The key is the first capture group if the pattern
+  /// has one and it participates in the match, or else the whole match. A line
+  /// on which the pattern does not match has an empty key.
+  #[cfg(feature = "regex")]
+  pub pattern: Option<Regex>,
+  /// How keys are compared.
+  pub comparison: Comparison,
+  /// Whether the order is reversed, so that greater keys come first.
+  pub reverse: bool,
+}
+
+impl Default for KeySpec {
+  /// Compares whole lines lexically, in ascending order.
+  fn default() -> Self {
+    KeySpec {
+      field: None,
+      separator: None,
+      #[cfg(feature = "regex")]
+      pattern: None,
+      comparison: Comparison::Lexical,
+      reverse: false,
+    }
+  }
+}
+
+impl KeySpec {
+  /// Returns the byte range of the key in `line`.
+  pub fn key_range(&self, line: &[u8]) -> Range<usize> {
+    let field = self.field_range(line);
+    #[cfg(feature = "regex")]
+    {
+      if let Some(pattern) = &self.pattern {
+        let captures = pattern.captures(&line[field.clone()]);
+        return match captures.and_then(|c| c.get(1).or_else(|| c.get(0))) {
+          Some(m) => field.start + m.start()..field.start + m.end(),
+          None => line.len()..line.len(),
+        };
+      }
+    }
+    field
+  }
+
+  /// Returns the byte range of the selected field in `line`.
+  fn field_range(&self, line: &[u8]) -> Range<usize> {
+    let index = match self.field {
+      None => return 0..line.len(),
+      Some(index) => index.saturating_sub(1),
+    };
+    let mut fields: Box<dyn Iterator<Item = &[u8]>> = match self.separator {
+      Some(separator) => Box::new(line.split(move |b| *b == separator)),
+      None => Box::new(line.split(|b| b.is_ascii_whitespace()).filter(|f| !f.is_empty())),
+    };
+    match fields.nth(index) {
+      Some(field) => {
+        let start = field.as_ptr() as usize - line.as_ptr() as usize;
+        start..start + field.len()
+      },
+      None => line.len()..line.len(),
+    }
+  }
+
+  /// Extracts the key of `line` and returns the two together.
+  pub fn keyed(&self, line: Vec<u8>) -> KeyedLine {
+    let range = self.key_range(&line);
+    let key = match self.comparison {
+      Comparison::Lexical => Key::Text(range),
+      Comparison::Numeric => Key::Number(
+        str::from_utf8(&line[range]).ok().and_then(|s| s.trim().parse().ok())),
+    };
+    KeyedLine { line, key, reverse: self.reverse, }
+  }
+}
+
+/// A key extracted from a line.
+#[derive(Clone, Debug)]
+enum Key {
+  /// The byte range of the key within the line.
+  Text(Range<usize>),
+  /// The parsed value of the key, if it is a number.
+  Number(Option<f64>),
+}
+
+/// A line of text together with its key. Lines are ordered by key, following
+/// the `KeySpec` that created them, and lines with equal keys are equal.
+#[derive(Clone, Debug)]
+pub struct KeyedLine {
+  line: Vec<u8>,
+  key: Key,
+  reverse: bool,
+}
+
+impl KeyedLine {
+  /// Returns the line, without its key.
+  pub fn line(&self) -> &[u8] {
+    &self.line
+  }
+
+  /// Returns the line, consuming `self`.
+  pub fn into_line(self) -> Vec<u8> {
+    self.line
+  }
+}
+
+impl Ord for KeyedLine {
+  fn cmp(&self, other: &Self) -> Ordering {
+    let ordering = match (&self.key, &other.key) {
+      (Key::Text(a), Key::Text(b)) => self.line[a.clone()].cmp(&other.line[b.clone()]),
+      (Key::Number(a), Key::Number(b)) => match (a, b) {
+        (Some(a), Some(b)) => a.total_cmp(b),
+        (a, b) => a.is_some().cmp(&b.is_some()),
+      },
+      _ => panic!("comparing lines keyed by different comparisons"),
+    };
+    if self.reverse {
+      ordering.reverse()
+    } else {
+      ordering
+    }
+  }
+}
+
+impl PartialOrd for KeyedLine {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl PartialEq for KeyedLine {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl Eq for KeyedLine {}
+
+#[cfg(test)]
+mod tests {
+  use super::{Comparison, KeySpec};
+
+  fn key(spec: &KeySpec, line: &str) -> String {
+    String::from_utf8(line.as_bytes()[spec.key_range(line.as_bytes())].to_vec()).unwrap()
+  }
+
+  #[test]
+  fn fields() {
+    let whitespace = KeySpec { field: Some(2), ..KeySpec::default() };
+    assert_eq!(key(&whitespace, "  a \t bb  c"), "bb");
+    assert_eq!(key(&whitespace, "a"), "");
+    let comma = KeySpec { field: Some(3), separator: Some(b','), ..KeySpec::default() };
+    assert_eq!(key(&comma, "a,,c,d"), "c");
+    assert_eq!(key(&KeySpec::default(), "whole line"), "whole line");
+  }
+
+  #[cfg(feature = "regex")]
+  #[test]
+  fn patterns() {
+    use regex::bytes::Regex;
+    let latency = KeySpec { pattern: Some(Regex::new(r"took (\d+)ms").unwrap()), ..KeySpec::default() };
+    assert_eq!(key(&latency, "GET / took 35ms"), "35");
+    assert_eq!(key(&latency, "GET / failed"), "");
+    let word = KeySpec { field: Some(2), pattern: Some(Regex::new("[a-z]+").unwrap()), ..KeySpec::default() };
+    assert_eq!(key(&word, "abc 12def34 ghi"), "def");
+  }
+
+  #[test]
+  fn ordering() {
+    let numeric = KeySpec { comparison: Comparison::Numeric, ..KeySpec::default() };
+    let mut lines: Vec<_> = ["10", "9", "-1.5", "x", " 2 "].iter()
+      .map(|s| numeric.keyed(s.as_bytes().to_vec()))
+      .collect();
+    lines.sort();
+    let sorted: Vec<&[u8]> = lines.iter().map(|l| l.line()).collect();
+    assert_eq!(sorted, vec![&b"x"[..], b"-1.5", b" 2 ", b"9", b"10"]);
+
+    let reverse = KeySpec { reverse: true, ..KeySpec::default() };
+    assert!(reverse.keyed(b"b".to_vec()) < reverse.keyed(b"a".to_vec()));
+    assert!(KeySpec::default().keyed(b"a".to_vec()) < KeySpec::default().keyed(b"b".to_vec()));
+  }
+}
@@ -35,13 +35,64 @@
 //! modest size, however, the number of comparisons that are required to perform
 //! an operation is less than the number of comparisons that a binary heap
 //! makes. If the cost of making a comparison truly dominates running time, then
-//! `WeakHeap` may be faster than `BinaryHeap` in practice.
+//! `WeakHeap` may be faster than `BinaryHeap` in practice. The
+//! `expensive-comparisons` benchmark sweeps the cost of a comparison and
+//! reports where this crossover occurs.
+//!
+//! ## Checking invariants
+//!
+//! `WeakHeap::check_invariants` verifies that every element is no greater than
+//! its distinguished ancestor. Building with the `debug-invariants` feature
+//! runs this check after every `push` and `pop` and panics on a violation,
+//! which is useful for tracking down a misbehaving `Ord` implementation.
+//!
+//! ## Statistics
+//!
+//! Building with the `stats` feature keeps counts of the pushes, pops,
+//! comparisons, element moves and valence flips each heap makes, available
+//! through `WeakHeap::stats`. For finer detail, such as the distribution of
+//! sift lengths, attach an `Observer` instead.
+//!
+//! ## Serialization
+//!
+//! Building with the `serde` feature implements `Serialize` and `Deserialize`
+//! for `WeakHeap`. The serialized form records the heap's layout, including its
+//! valence bits, so a heap is restored in O(n) time without any comparisons.
+//! The `snapshot` module provides a dependency-free binary format with the same
+//! property for element types that implement `codec::Codec`.
 
 use std::cmp::Ord;
+use std::error::Error;
 use std::fmt;
+use std::iter::FromIterator;
+use std::mem;
+use std::ops::{Deref, DerefMut};
 use std::ptr;
+use crate::observer::{Hooks, Sift};
 
+pub use crate::observer::Observer;
+pub use crate::priority_queue::{FalliblePriorityQueue, PriorityQueue, SharedPriorityQueue, StdBinaryHeap};
+
+pub mod async_queue;
 #[cfg(feature = "benchmark-definitions")] pub mod benches;
+pub mod channel;
+pub mod codec;
+pub mod counted;
+pub mod delay_queue;
+pub mod external_sort;
+pub mod keys;
+pub mod multiqueue;
+pub mod observer;
+pub mod paged;
+mod priority_queue;
+pub mod render;
+#[cfg(feature = "arbitrary")] pub mod script;
+pub mod select;
+#[cfg(feature = "serde")] mod serialization;
+pub mod snapshot;
+#[cfg(feature = "stats")] pub mod stats;
+pub mod sync;
+pub mod tree;
 
 /// An entry in the heap, consisting of a bit that indicates whether the roles
 /// of its left and right children are swapped, and the actual value being
@@ -57,6 +108,86 @@ struct HeapEntry<T: fmt::Debug> {
   valence: bool,
 }
 
+/// A hole left in the heap by moving an element out of it. The element is
+/// written back into the hole when it is dropped, so the heap owns every
+/// element again even if a comparison or an observer panics.
+struct Hole<'a, T: fmt::Debug> {
+  data: &'a mut [HeapEntry<T>],
+  element: mem::ManuallyDrop<T>,
+  offset: usize,
+}
+
+impl<'a, T: fmt::Debug> Hole<'a, T> {
+  /// Moves the element at `offset` out of `data`, leaving a hole.
+  ///
+  /// Unsafe because `offset` must be in bounds.
+  unsafe fn new(data: &'a mut [HeapEntry<T>], offset: usize) -> Self {
+    debug_assert!(offset < data.len());
+    let element = ptr::read(&data.get_unchecked(offset).value);
+    Hole { data, element: mem::ManuallyDrop::new(element), offset, }
+  }
+
+  /// Moves the element at `offset` into the hole, flipping the valence of the
+  /// entry it moves into, and leaves the hole at `offset`. Returns the new
+  /// valence of that entry.
+  ///
+  /// Unsafe because `offset` must be in bounds and differ from the hole's.
+  unsafe fn move_to(&mut self, offset: usize) -> bool {
+    debug_assert!(offset != self.offset && offset < self.data.len());
+    let from: *const T = &self.data.get_unchecked(offset).value;
+    let hole = self.data.get_unchecked_mut(self.offset);
+    ptr::copy_nonoverlapping(from, &mut hole.value, 1);
+    hole.valence = !hole.valence;
+    self.offset = offset;
+    hole.valence
+  }
+}
+
+impl<'a, T: fmt::Debug> Drop for Hole<'a, T> {
+  fn drop(&mut self) {
+    unsafe {
+      let element = mem::ManuallyDrop::take(&mut self.element);
+      ptr::write(&mut self.data.get_unchecked_mut(self.offset).value, element);
+    }
+  }
+}
+
+/// Returns the offset of the distinguished ancestor of the element at `offset`.
+fn distinguished_ancestor_offset<T: fmt::Debug>(data: &[HeapEntry<T>], mut offset: usize) -> usize {
+  debug_assert!(offset > 0 && offset < data.len());
+  let mut parent_offset = offset / 2;
+  let mut parent_valence = unsafe { data.get_unchecked(parent_offset).valence };
+  while (offset & 1) == (parent_valence as usize) {
+    offset = parent_offset;
+    parent_offset /= 2;
+    parent_valence = unsafe { data.get_unchecked(parent_offset).valence };
+  }
+  parent_offset
+}
+
+/// A violation of the weak heap ordering, as reported by
+/// `WeakHeap::check_invariants`.
+#[derive(Debug)]
+pub struct InvariantViolation<'a, T: fmt::Debug> {
+  /// The offset of the element that is out of order.
+  pub offset: usize,
+  /// The offset of the distinguished ancestor of the element at `offset`.
+  pub ancestor_offset: usize,
+  /// The element at `offset`.
+  pub value: &'a T,
+  /// The element at `ancestor_offset`, which is less than `value`.
+  pub ancestor_value: &'a T,
+}
+
+impl<'a, T: fmt::Debug> fmt::Display for InvariantViolation<'a, T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "element {:?} at offset {} is greater than its distinguished ancestor {:?} at offset {}",
+           self.value, self.offset, self.ancestor_value, self.ancestor_offset)
+  }
+}
+
+impl<'a, T: fmt::Debug> Error for InvariantViolation<'a, T> {}
+
 /// A weak heap data structure. This implementation is a max-heap.
 ///
 /// ```rust
@@ -73,26 +204,71 @@ struct HeapEntry<T: fmt::Debug> {
 /// assert_eq!(ordered, vec![10, 7, 5]);
 /// # }
 /// ```
+///
+/// The steps the heap takes can be watched by creating it with
+/// `with_observer`. See the `observer` module.
 #[derive(Debug)]
-pub struct WeakHeap<T: fmt::Debug + Ord> {
+pub struct WeakHeap<T: fmt::Debug + Ord, O: Observer = ()> {
   data: Vec<HeapEntry<T>>,
+  hooks: Hooks<O>,
+}
+
+impl<T: fmt::Debug + Ord> Default for WeakHeap<T> {
+  fn default() -> Self {
+    WeakHeap::new()
+  }
 }
 
 impl<T: fmt::Debug + Ord> WeakHeap<T> {
   /// Creates a new heap with a default capacity.
   pub fn new() -> Self {
+    WeakHeap::with_observer(())
+  }
+
+  /// Creates a new heap with capacity for at least `cap` elements.
+  pub fn with_capacity(cap: usize) -> Self {
+    WeakHeap::with_capacity_and_observer(cap, ())
+  }
+
+  /// Builds a heap from `values` in O(n) time.
+  pub(crate) fn from_vec(values: Vec<T>) -> Self {
+    let mut heap = WeakHeap {
+      data: values.into_iter().map(|value| HeapEntry { valence: false, value, }).collect(),
+      hooks: Hooks::new(()),
+    };
+    heap.rebuild();
+    heap
+  }
+}
+
+impl<T: fmt::Debug + Ord, O: Observer> WeakHeap<T, O> {
+  /// Creates a new heap that reports the steps it takes to `observer`.
+  pub fn with_observer(observer: O) -> Self {
     WeakHeap {
       data: Vec::new(),
+      hooks: Hooks::new(observer),
     }
   }
 
-  /// Creates a new heap with capacity for at least `cap` elements.
-  pub fn with_capacity(cap: usize) -> Self {
+  /// Creates a new heap with capacity for at least `cap` elements that reports
+  /// the steps it takes to `observer`.
+  pub fn with_capacity_and_observer(cap: usize, observer: O) -> Self {
     WeakHeap {
       data: Vec::with_capacity(cap),
+      hooks: Hooks::new(observer),
     }
   }
 
+  /// Returns a reference to the heap's observer.
+  pub fn observer(&self) -> &O {
+    &self.hooks.observer
+  }
+
+  /// Returns a mutable reference to the heap's observer.
+  pub fn observer_mut(&mut self) -> &mut O {
+    &mut self.hooks.observer
+  }
+
   /// Returns the number of elements in the heap.
   pub fn len(&self) -> usize {
     self.data.len()
@@ -110,11 +286,20 @@ impl<T: fmt::Debug + Ord> WeakHeap<T> {
   }
 
   /// Pushes `value` onto the heap.
+  #[allow(clippy::redundant_field_names, clippy::manual_is_multiple_of)]
   pub fn push(&mut self, value: T) {
     let offset = self.len();
     self.data.push(HeapEntry { valence: false, value: value, });
-    unsafe { self.data.get_unchecked_mut(offset / 2).valence |= offset % 2 == 0; }
+    self.hooks.pushed(offset + 1);
+    self.hooks.moved(1);
+    let parent = unsafe { self.data.get_unchecked_mut(offset / 2) };
+    if offset % 2 == 0 && !parent.valence {
+      parent.valence = true;
+      self.hooks.flip(offset / 2, true);
+    }
     self.sift_up(offset);
+    #[cfg(feature = "debug-invariants")]
+    self.assert_invariants("push");
   }
 
   /// Removes the top element from the heap and returns it, or returns `None` if
@@ -124,14 +309,119 @@ impl<T: fmt::Debug + Ord> WeakHeap<T> {
       None
     } else {
       let result = Some(self.data.swap_remove(0).value);
+      self.hooks.popped();
       if !self.is_empty() {
-        unsafe { self.data.get_unchecked_mut(0).valence = false; }
-        self.sift_down(1);
+        self.hooks.moved(1);
+        let root = unsafe { self.data.get_unchecked_mut(0) };
+        if root.valence {
+          root.valence = false;
+          self.hooks.flip(0, false);
+        }
+        self.sift_down_root();
       }
+      #[cfg(feature = "debug-invariants")]
+      self.assert_invariants("pop");
       result
     }
   }
 
+  /// Returns a mutable reference to the top element on the heap, or `None` if
+  /// the heap is empty. If the element is modified, the heap invariants are
+  /// restored when the returned `PeekMut` is dropped.
+  pub fn peek_mut(&mut self) -> Option<PeekMut<'_, T, O>> {
+    if self.is_empty() {
+      None
+    } else {
+      Some(PeekMut { heap: self, sift: false, })
+    }
+  }
+
+  /// Retains only the elements for which `f` returns `true`. The heap is
+  /// rebuilt in O(n) time if any element is removed.
+  pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+    let len = self.len();
+    self.data.retain(|entry| f(&entry.value));
+    if self.len() < len {
+      self.rebuild();
+    }
+    #[cfg(feature = "debug-invariants")]
+    self.assert_invariants("retain");
+  }
+
+  /// Moves all elements of `other` into this heap, leaving `other` empty.
+  pub fn append(&mut self, other: &mut Self) {
+    if self.len() < other.len() {
+      mem::swap(&mut self.data, &mut other.data);
+    }
+    self.extend(other.data.drain(..).map(|entry| entry.value));
+  }
+
+  /// Consumes the heap and returns its elements in ascending order.
+  pub fn into_sorted_vec(mut self) -> Vec<T> {
+    let mut sorted = Vec::with_capacity(self.len());
+    while let Some(x) = self.pop() {
+      sorted.push(x);
+    }
+    sorted.reverse();
+    sorted
+  }
+
+  /// Checks that every element in the heap is no greater than its
+  /// distinguished ancestor. Returns the first violation found, scanning in
+  /// order of increasing offset.
+  ///
+  /// This walks the entire heap and takes O(n log n) time. A violation
+  /// indicates an inconsistent `Ord` implementation or memory corruption.
+  pub fn check_invariants(&self) -> Result<(), InvariantViolation<'_, T>> {
+    for offset in 1..self.len() {
+      let ancestor_offset = self.distinguished_ancestor_offset(offset);
+      let value = &self.data[offset].value;
+      let ancestor_value = &self.data[ancestor_offset].value;
+      if ancestor_value < value {
+        return Err(InvariantViolation { offset, ancestor_offset, value, ancestor_value, });
+      }
+    }
+    Ok(())
+  }
+
+  /// Restores the heap invariants over all of `self.data`, whose elements may be
+  /// in any order. Valence bits are reset, and then each element is joined with
+  /// its distinguished ancestor, starting from the end of the heap. This makes
+  /// exactly `len() - 1` comparisons.
+  fn rebuild(&mut self) {
+    self.hooks.sift_start(Sift::Rebuild, 0);
+    for (offset, entry) in self.data.iter_mut().enumerate() {
+      if entry.valence {
+        entry.valence = false;
+        self.hooks.flip(offset, false);
+      }
+    }
+    for offset in (1..self.len()).rev() {
+      let ancestor_offset = self.distinguished_ancestor_offset(offset);
+      let (head, tail) = self.data.split_at_mut(offset);
+      let ancestor = &mut head[ancestor_offset];
+      let child = &mut tail[0];
+      self.hooks.compare(ancestor_offset, offset);
+      if ancestor.value < child.value {
+        mem::swap(&mut ancestor.value, &mut child.value);
+        child.valence = !child.valence;
+        self.hooks.swap(ancestor_offset, offset);
+        self.hooks.moved(2);
+        self.hooks.flip(offset, child.valence);
+      }
+    }
+    self.hooks.sift_end(Sift::Rebuild);
+    self.hooks.rebuilt(self.len());
+  }
+
+  /// Panics if `check_invariants` reports a violation after `operation`.
+  #[cfg(feature = "debug-invariants")]
+  fn assert_invariants(&self, operation: &str) {
+    if let Err(e) = self.check_invariants() {
+      panic!("weak heap invariant violated after {}: {}", operation, e);
+    }
+  }
+
   /// Returns the offset into `self.data` for the child of the element at
   /// `offset`.
   fn child_offset(&self, offset: usize) -> usize {
@@ -140,42 +430,84 @@ impl<T: fmt::Debug + Ord> WeakHeap<T> {
       .expect("child offset computation overflow")
   }
 
+  /// Returns the offset of the left child of the element at `offset` when
+  /// viewing the heap as a binary tree, or `None` if it has none. The left
+  /// child shares the element's distinguished ancestor. The root never has a
+  /// left child.
+  fn left_child_offset(&self, offset: usize) -> Option<usize> {
+    if offset == 0 {
+      return None;
+    }
+    Some(self.child_offset(offset)).filter(|child| *child < self.len())
+  }
+
+  /// Returns the offset of the right child of the element at `offset` when
+  /// viewing the heap as a binary tree, or `None` if it has none. The element
+  /// is the right child's distinguished ancestor.
+  fn right_child_offset(&self, offset: usize) -> Option<usize> {
+    let child = if offset == 0 {
+      1
+    } else {
+      2 * offset + 1 - self.data[offset].valence as usize
+    };
+    Some(child).filter(|child| *child < self.len())
+  }
+
+  /// Returns the offsets of the children of the element at `offset` when
+  /// viewing the heap as an N-ary tree: its right child, followed by each
+  /// element reached by following left children from there.
+  fn children_offsets(&self, offset: usize) -> impl Iterator<Item = usize> + '_ {
+    let mut next = self.right_child_offset(offset);
+    std::iter::from_fn(move || {
+      let child = next?;
+      next = self.left_child_offset(child);
+      Some(child)
+    })
+  }
+
   /// Returns the offset into `self.data` for the distinguished ancestor of the
   /// element at `offset`. The distinguished ancestor of an element is its
   /// immediate parent when viewing the heap as an N-ary tree.
-  fn distinguished_ancestor_offset(&self, mut offset: usize) -> usize {
-    debug_assert!(offset > 0);
-    let mut parent_offset = offset / 2;
-    let mut parent_valence = unsafe { self.data.get_unchecked(parent_offset).valence };
-    while (offset & 1) == (parent_valence as usize) {
-      offset = parent_offset;
-      parent_offset /= 2;
-      parent_valence = unsafe { self.data.get_unchecked(parent_offset).valence };
-    }
-    parent_offset
+  fn distinguished_ancestor_offset(&self, offset: usize) -> usize {
+    distinguished_ancestor_offset(&self.data, offset)
   }
 
   /// Sifts the element at `offset` up in the heap so that the heap invariants
   /// are satisfied. This is done by repeatedly swapping an item with its
   /// distinguished ancestor until its distinguished ancestor is greater than it
   /// is.
-  fn sift_up(&mut self, mut offset: usize) {
-    unsafe {
-      let element = ptr::read(&self.data.get_unchecked(offset).value);
-      let mut ancestor_offset;
-      while offset > 0 {
-        ancestor_offset = self.distinguished_ancestor_offset(offset);
-        let ancestor_value: *const _ = &self.data.get_unchecked(ancestor_offset).value;
-        if *ancestor_value >= element {
+  fn sift_up(&mut self, offset: usize) {
+    self.hooks.sift_start(Sift::Up, offset);
+    {
+      let hooks = &mut self.hooks;
+      let mut hole = unsafe { Hole::new(&mut self.data, offset) };
+      while hole.offset > 0 {
+        let offset = hole.offset;
+        let ancestor_offset = distinguished_ancestor_offset(hole.data, offset);
+        hooks.compare(ancestor_offset, offset);
+        let ancestor = unsafe { hole.data.get_unchecked(ancestor_offset) };
+        if ancestor.value >= *hole.element {
           break;
         }
-        let offset_item = self.data.get_unchecked_mut(offset);
-        ptr::copy_nonoverlapping(ancestor_value, &mut offset_item.value, 1);
-        offset_item.valence = !offset_item.valence;
-        offset = ancestor_offset;
+        let valence = unsafe { hole.move_to(ancestor_offset) };
+        hooks.swap(ancestor_offset, offset);
+        hooks.moved(1);
+        hooks.flip(offset, valence);
+      }
+      // The hole's final write places the sifted element, so count it too.
+      if hole.offset != offset {
+        hooks.moved(1);
       }
-      ptr::write(&mut self.data.get_unchecked_mut(offset).value, element);
     }
+    self.hooks.sift_end(Sift::Up);
+  }
+
+  /// Sifts the top of the heap down, starting from its only child, and reports
+  /// the sift to the observer.
+  fn sift_down_root(&mut self) {
+    self.hooks.sift_start(Sift::Down, 1);
+    self.sift_down(1);
+    self.hooks.sift_end(Sift::Down);
   }
 
   /// Sifts the top of the heap down so that the heap invariants are
@@ -196,20 +528,98 @@ impl<T: fmt::Debug + Ord> WeakHeap<T> {
     unsafe {
       let head_value: *mut T = &mut self.data.get_unchecked_mut(0).value;
       let child = &mut self.data.get_unchecked_mut(child_offset);
+      self.hooks.compare(0, child_offset);
       if *head_value < child.value {
         ptr::swap_nonoverlapping(head_value, &mut child.value, 1);
         child.valence = !child.valence;
+        self.hooks.swap(0, child_offset);
+        self.hooks.moved(2);
+        self.hooks.flip(child_offset, child.valence);
       }
     }
   }
 }
 
+impl<T: fmt::Debug + Ord, O: Observer> Extend<T> for WeakHeap<T, O> {
+  fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+    let iter = iter.into_iter();
+    self.data.reserve(iter.size_hint().0);
+    for value in iter {
+      self.push(value);
+    }
+  }
+}
+
+impl<T: fmt::Debug + Ord> FromIterator<T> for WeakHeap<T> {
+  fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+    let mut heap = WeakHeap::new();
+    heap.extend(iter);
+    heap
+  }
+}
+
+/// A mutable reference to the top element of a `WeakHeap`, as returned by
+/// `WeakHeap::peek_mut`.
+pub struct PeekMut<'a, T: fmt::Debug + Ord, O: Observer = ()> {
+  heap: &'a mut WeakHeap<T, O>,
+  /// Whether the top element may have been modified, in which case it must be
+  /// sifted down when this is dropped.
+  sift: bool,
+}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> PeekMut<'a, T, O> {
+  /// Removes the peeked element from the heap and returns it.
+  pub fn pop(mut this: PeekMut<'a, T, O>) -> T {
+    this.sift = false;
+    this.heap.pop().unwrap()
+  }
+}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> fmt::Debug for PeekMut<'a, T, O> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_tuple("PeekMut").field(&**self).finish()
+  }
+}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> Deref for PeekMut<'a, T, O> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.heap.data[0].value
+  }
+}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> DerefMut for PeekMut<'a, T, O> {
+  fn deref_mut(&mut self) -> &mut T {
+    self.sift = true;
+    &mut self.heap.data[0].value
+  }
+}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> Drop for PeekMut<'a, T, O> {
+  fn drop(&mut self) {
+    if self.sift {
+      self.heap.sift_down_root();
+      #[cfg(feature = "debug-invariants")]
+      self.heap.assert_invariants("peek_mut");
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
-  use super::WeakHeap;
+  use super::{PeekMut, WeakHeap};
+  use proptest::prelude::*;
   use rand::rngs::StdRng;
   use rand::{Rng, SeedableRng};
+  use std::cell::Cell;
+  use std::cmp::Ordering;
+  use std::collections::BinaryHeap;
+  use std::fmt;
+  use std::mem;
+  use std::rc::Rc;
 
+  #[allow(clippy::useless_conversion)]
   pub fn get_values(size: usize) -> Vec<i32> {
     let seed: [u8; 32] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31];
     let mut rng: StdRng = SeedableRng::from_seed(seed);
@@ -236,6 +646,7 @@ mod tests {
   }
 
   #[test]
+  #[allow(clippy::map_clone)]
   fn multiple_pop() {
     let mut t = WeakHeap::new();
     let values = [0usize, 1, 2, 3, 4, 5, 6];
@@ -269,4 +680,375 @@ mod tests {
     }
     assert_eq!(heap_sorted, sorted);
   }
+
+  #[test]
+  fn peek_mut() {
+    let mut heap: WeakHeap<i32> = get_values(60).into_iter().collect();
+    let mut sorted = heap.data.iter().map(|entry| entry.value).collect::<Vec<_>>();
+    sorted.sort();
+    *heap.peek_mut().unwrap() = i32::MIN;
+    assert!(heap.check_invariants().is_ok());
+    assert_eq!(heap.peek(), sorted.get(sorted.len() - 2));
+    assert_eq!(PeekMut::pop(heap.peek_mut().unwrap()), sorted[sorted.len() - 2]);
+    assert_eq!(heap.len(), 59);
+    assert!(heap.check_invariants().is_ok());
+  }
+
+  #[test]
+  fn retain() {
+    let mut heap: WeakHeap<i32> = (0..60).collect();
+    heap.retain(|x| x % 3 == 0);
+    assert!(heap.check_invariants().is_ok());
+    assert_eq!(heap.into_sorted_vec(), (0..60).filter(|x| x % 3 == 0).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn append() {
+    let mut heap: WeakHeap<i32> = (0..10).collect();
+    let mut other: WeakHeap<i32> = (10..40).collect();
+    heap.append(&mut other);
+    assert!(other.is_empty());
+    assert!(heap.check_invariants().is_ok());
+    assert_eq!(heap.into_sorted_vec(), (0..40).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn invariants_hold() {
+    let mut heap = WeakHeap::new();
+    assert!(heap.check_invariants().is_ok());
+    for x in get_values(60) {
+      heap.push(x);
+      assert!(heap.check_invariants().is_ok());
+    }
+    while heap.pop().is_some() {
+      assert!(heap.check_invariants().is_ok());
+    }
+  }
+
+  #[test]
+  fn invariant_violation_reported() {
+    let mut heap = WeakHeap::new();
+    for x in 0..8 {
+      heap.push(x);
+    }
+    heap.data[5].value = 100;
+    let violation = heap.check_invariants().unwrap_err();
+    assert_eq!(violation.offset, 5);
+    assert_eq!(violation.ancestor_offset, heap.distinguished_ancestor_offset(5));
+    assert_eq!(*violation.value, 100);
+    assert_eq!(violation.ancestor_value, &heap.data[violation.ancestor_offset].value);
+    assert!(violation.to_string().contains("at offset 5"));
+  }
+
+  /// An operation in a sequence that is replayed against both `WeakHeap` and
+  /// `BinaryHeap`.
+  #[derive(Clone, Debug)]
+  enum Op {
+    Push(i32),
+    Pop,
+    Peek,
+    PeekMut(i32),
+    Extend(Vec<i32>),
+    Append(Vec<i32>),
+    Rebuild(Vec<i32>),
+    /// Retains elements whose keys are not multiples of the given value.
+    Retain(i32),
+    IntoSortedVec,
+  }
+
+  fn op_strategy() -> impl Strategy<Value = Op> {
+    // Keys are drawn from a narrow range so that duplicates are common.
+    let keys = prop::collection::vec(0..16i32, 0..32);
+    prop_oneof![
+      4 => (0..16i32).prop_map(Op::Push),
+      3 => Just(Op::Pop),
+      1 => Just(Op::Peek),
+      1 => (0..16i32).prop_map(Op::PeekMut),
+      1 => keys.clone().prop_map(Op::Extend),
+      1 => keys.clone().prop_map(Op::Append),
+      1 => keys.prop_map(Op::Rebuild),
+      1 => (2..5i32).prop_map(Op::Retain),
+      1 => Just(Op::IntoSortedVec),
+    ]
+  }
+
+  fn ops_strategy() -> impl Strategy<Value = Vec<Op>> {
+    prop::collection::vec(op_strategy(), 0..200)
+  }
+
+  /// Replays `ops` against a `WeakHeap` and a `BinaryHeap` whose elements are
+  /// built from keys by `make`, comparing the keys of the elements they yield.
+  fn check_against_model<T, M, K>(ops: &[Op], make: M, key: K) -> Result<(), TestCaseError>
+  where T: fmt::Debug + Ord, M: Fn(i32) -> T, K: Fn(&T) -> i32 {
+    let mut heap = WeakHeap::new();
+    let mut model = BinaryHeap::new();
+    for op in ops {
+      match op {
+        Op::Push(k) => {
+          heap.push(make(*k));
+          model.push(make(*k));
+        },
+        Op::Pop => prop_assert_eq!(heap.pop().map(|x| key(&x)), model.pop().map(|x| key(&x))),
+        Op::Peek => prop_assert_eq!(heap.peek().map(&key), model.peek().map(&key)),
+        Op::PeekMut(k) => {
+          if let Some(mut top) = heap.peek_mut() {
+            *top = make(*k);
+          }
+          if let Some(mut top) = model.peek_mut() {
+            *top = make(*k);
+          }
+        },
+        Op::Extend(ks) => {
+          heap.extend(ks.iter().map(|k| make(*k)));
+          model.extend(ks.iter().map(|k| make(*k)));
+        },
+        Op::Append(ks) => {
+          heap.append(&mut ks.iter().map(|k| make(*k)).collect());
+          model.append(&mut ks.iter().map(|k| make(*k)).collect());
+        },
+        Op::Rebuild(ks) => {
+          heap = ks.iter().map(|k| make(*k)).collect();
+          model = ks.iter().map(|k| make(*k)).collect();
+        },
+        Op::Retain(m) => {
+          heap.retain(|x| key(x) % m != 0);
+          model.retain(|x| key(x) % m != 0);
+        },
+        Op::IntoSortedVec => {
+          let sorted: Vec<i32> = mem::take(&mut heap).into_sorted_vec().iter().map(&key).collect();
+          let expected: Vec<i32> = mem::take(&mut model).into_sorted_vec().iter().map(&key).collect();
+          prop_assert_eq!(sorted, expected);
+        },
+      }
+      prop_assert_eq!(heap.len(), model.len());
+      prop_assert_eq!(heap.peek().map(&key), model.peek().map(&key));
+      if let Err(e) = heap.check_invariants() {
+        return Err(TestCaseError::fail(e.to_string()));
+      }
+    }
+    while let Some(x) = model.pop() {
+      prop_assert_eq!(heap.pop().map(|y| key(&y)), Some(key(&x)));
+    }
+    prop_assert!(heap.is_empty());
+    Ok(())
+  }
+
+  /// A zero-sized type whose values are all equal.
+  #[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+  struct Unit;
+
+  /// An element that tracks the number of live instances, to catch leaked or
+  /// doubly-dropped values.
+  #[derive(Debug)]
+  struct Tracked {
+    key: i32,
+    live: Rc<Cell<isize>>,
+  }
+
+  impl Tracked {
+    fn new(key: i32, live: &Rc<Cell<isize>>) -> Self {
+      live.set(live.get() + 1);
+      Tracked { key, live: live.clone(), }
+    }
+  }
+
+  impl Drop for Tracked {
+    fn drop(&mut self) {
+      self.live.set(self.live.get() - 1);
+    }
+  }
+
+  impl PartialEq for Tracked {
+    fn eq(&self, other: &Self) -> bool {
+      self.key == other.key
+    }
+  }
+
+  impl Eq for Tracked {}
+
+  impl PartialOrd for Tracked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+      Some(self.cmp(other))
+    }
+  }
+
+  impl Ord for Tracked {
+    fn cmp(&self, other: &Self) -> Ordering {
+      self.key.cmp(&other.key)
+    }
+  }
+
+  /// An observer that panics on its first swap.
+  struct PanicOnSwap;
+
+  impl crate::observer::Observer for PanicOnSwap {
+    fn swap(&mut self, _a: usize, _b: usize) {
+      panic!("observer panicked");
+    }
+  }
+
+  #[test]
+  fn panicking_observer_drops_each_element_once() {
+    let live = Rc::new(Cell::new(0));
+    {
+      let mut heap = WeakHeap::with_observer(PanicOnSwap);
+      heap.push(Tracked::new(1, &live));
+      let pushed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        heap.push(Tracked::new(2, &live));
+      }));
+      assert!(pushed.is_err());
+      assert_eq!(heap.len(), 2);
+      assert_eq!(live.get(), 2);
+      let mut keys: Vec<i32> = heap.data.iter().map(|entry| entry.value.key).collect();
+      keys.sort();
+      assert_eq!(keys, vec![1, 2]);
+    }
+    assert_eq!(live.get(), 0);
+  }
+
+  #[test]
+  fn pop_reports_root_valence_reset() {
+    let mut heap = WeakHeap::with_observer(crate::observer::Recorder::default());
+    heap.extend(0..8);
+    // `pop` moves the last element, which has no children, to the root.
+    heap.data[7].valence = true;
+    heap.observer_mut().take();
+    heap.pop();
+    assert_eq!(heap.observer().steps.first(), Some(&crate::observer::Step::Flip(0, false)));
+  }
+
+  proptest! {
+    #[test]
+    fn model_i32(ops in ops_strategy()) {
+      check_against_model(&ops, |k| k, |x| *x)?;
+    }
+
+    #[test]
+    fn model_zero_sized(ops in ops_strategy()) {
+      check_against_model(&ops, |_| Unit, |_| 0)?;
+    }
+
+    #[test]
+    fn model_drop(ops in ops_strategy()) {
+      let live = Rc::new(Cell::new(0));
+      check_against_model(&ops, |k| Tracked::new(k, &live), |x| x.key)?;
+      prop_assert_eq!(live.get(), 0);
+    }
+  }
+
+  /// Rearranges `values` into the next lexicographically greater permutation,
+  /// returning `false` (and leaving `values` sorted) once the last permutation
+  /// has been passed.
+  fn next_permutation(values: &mut [usize]) -> bool {
+    let pivot = match values.windows(2).rposition(|w| w[0] < w[1]) {
+      Some(pivot) => pivot,
+      None => {
+        values.reverse();
+        return false;
+      },
+    };
+    let successor = values.iter().rposition(|x| *x > values[pivot]).unwrap();
+    values.swap(pivot, successor);
+    values[pivot + 1..].reverse();
+    true
+  }
+
+  /// Returns every sequence of `n` pushes and `n` pops in which no pop is
+  /// applied to an empty heap. `true` denotes a push.
+  fn interleavings(n: usize) -> Vec<Vec<bool>> {
+    fn extend(n: usize, pushes: usize, pops: usize, prefix: &mut Vec<bool>, out: &mut Vec<Vec<bool>>) {
+      if pops == n {
+        out.push(prefix.clone());
+        return;
+      }
+      if pushes < n {
+        prefix.push(true);
+        extend(n, pushes + 1, pops, prefix, out);
+        prefix.pop();
+      }
+      if pops < pushes {
+        prefix.push(false);
+        extend(n, pushes, pops + 1, prefix, out);
+        prefix.pop();
+      }
+    }
+    let mut out = Vec::new();
+    extend(n, 0, 0, &mut Vec::new(), &mut out);
+    out
+  }
+
+  /// Pushes `values` in order, interleaved with pops as directed by `script`,
+  /// checking each popped value and the heap invariants after every step.
+  fn check_script(values: &[usize], script: &[bool]) {
+    let mut heap = WeakHeap::new();
+    let mut expected: Vec<usize> = Vec::new();
+    let mut remaining = values.iter();
+    for &is_push in script {
+      if is_push {
+        let x = *remaining.next().unwrap();
+        heap.push(x);
+        let i = expected.binary_search(&x).unwrap_or_else(|i| i);
+        expected.insert(i, x);
+      } else {
+        assert_eq!(heap.pop(), expected.pop(), "values {:?}, script {:?}", values, script);
+      }
+      if let Err(e) = heap.check_invariants() {
+        panic!("{} (values {:?}, script {:?})", e, values, script);
+      }
+      assert_eq!(heap.peek(), expected.last());
+    }
+    assert!(heap.is_empty());
+  }
+
+  #[test]
+  fn exhaustive_permutations() {
+    for n in 0..=9 {
+      let mut values: Vec<usize> = (0..n).collect();
+      let script: Vec<bool> = (0..2 * n).map(|i| i < n).collect();
+      loop {
+        check_script(&values, &script);
+        if !next_permutation(&mut values) {
+          break;
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn exhaustive_duplicates() {
+    // Every sequence of `n` values drawn from `0..n` covers every pattern of
+    // ties among `n` elements.
+    for n in 0..=6 {
+      let script: Vec<bool> = (0..2 * n).map(|i| i < n).collect();
+      let mut values = vec![0; n];
+      loop {
+        check_script(&values, &script);
+        match values.iter().rposition(|x| *x + 1 < n) {
+          Some(i) => {
+            values[i] += 1;
+            for x in &mut values[i + 1..] {
+              *x = 0;
+            }
+          },
+          None => break,
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn exhaustive_interleavings() {
+    for n in 0..=7 {
+      let scripts = interleavings(n);
+      let mut values: Vec<usize> = (0..n).collect();
+      loop {
+        for script in &scripts {
+          check_script(&values, script);
+        }
+        if !next_permutation(&mut values) {
+          break;
+        }
+      }
+    }
+  }
 }
@@ -0,0 +1,284 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A relaxed concurrent priority queue, the MultiQueue of Rihani, Sanders and
+//! Dementiev ("MultiQueues: Simple Relaxed Concurrent Priority Queues", SPAA
+//! 2015), built from `WeakHeap` shards.
+//!
+//! A `MultiQueue` for `p` threads holds `c * p` shards, each a `WeakHeap`
+//! behind its own lock. A push goes to a random shard. A pop samples two shards
+//! at random and removes the greater of their tops. Threads rarely contend for
+//! the same shard, so throughput scales with the number of threads where a
+//! single locked heap such as `SyncWeakHeap` does not.
+//!
+//! # Rank error
+//!
+//! The price is that a pop need not return the greatest element. The *rank* of
+//! a popped element is the number of elements in the queue that were greater
+//! than it. With `n` shards, when pushes and pops do not overlap in time, the
+//! expected rank of every pop is `O(n)` and the greatest rank over a run of
+//! pops is `O(n log n)` with high probability, independent of the number of
+//! elements (Alistarh, Kopinsky, Li and Nadiradze, "The Power of Choice in
+//! Priority Scheduling", PODC 2017). Choosing the better of two shards is what
+//! bounds the error: popping from a single random shard lets it grow without
+//! bound. Concurrent operations add to the error by the number of operations
+//! in flight, and in practice the mean rank stays a small multiple of `n`, so
+//! `c` trades throughput against order. No element is ever lost, and no
+//! guarantee is made about the relative order of elements pushed by a thread.
+//!
+//! ```rust
+//! # use weak_heap::multiqueue::MultiQueue;
+//! # fn main() {
+//! let queue = MultiQueue::new(4);
+//! for x in 0..100 {
+//!   queue.push(x);
+//! }
+//! let mut popped = Vec::new();
+//! while let Some(x) = queue.pop() {
+//!   popped.push(x);
+//! }
+//! // Roughly, but not exactly, descending.
+//! assert_ne!(popped.first(), Some(&0));
+//! popped.sort_unstable();
+//! assert_eq!(popped, (0..100).collect::<Vec<_>>());
+//! # }
+//! ```
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::thread;
+use crate::WeakHeap;
+
+/// The default number of shards per thread, `c`.
+pub const DEFAULT_SHARDS_PER_THREAD: usize = 2;
+
+/// The number of two-shard samples a pop attempts, including those that find a
+/// shard locked, before it falls back to visiting every shard in turn.
+const SAMPLES: usize = 4;
+
+thread_local! {
+  /// The state of this thread's xorshift generator, which picks shards.
+  static RNG: Cell<u64> = Cell::new({
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0x9e37_79b9_7f4a_7c15);
+    format!("{:?}", thread::current().id()).bytes().for_each(|b| hasher.write_u8(b));
+    hasher.finish() | 1
+  });
+}
+
+/// Returns a random index less than `n`.
+fn random_index(n: usize) -> usize {
+  RNG.with(|rng| {
+    let mut x = rng.get();
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    rng.set(x);
+    (((x >> 32) * n as u64) >> 32) as usize
+  })
+}
+
+/// A relaxed priority queue whose pops return an element close to, but not
+/// necessarily, the greatest. See the module documentation for how close.
+///
+/// A panic while a shard is locked, for example from an `Ord` implementation,
+/// may leave that shard out of order and poisons it. The next operation that
+/// visits a poisoned shard discards its elements and clears the poison, so the
+/// queue stays usable but loses whatever the shard held.
+#[derive(Debug)]
+pub struct MultiQueue<T: Debug + Ord> {
+  shards: Vec<Mutex<WeakHeap<T>>>,
+}
+
+impl<T: Debug + Ord> MultiQueue<T> {
+  /// Creates a queue for `threads` threads, with `DEFAULT_SHARDS_PER_THREAD`
+  /// shards for each.
+  pub fn new(threads: usize) -> Self {
+    MultiQueue::with_shards_per_thread(threads, DEFAULT_SHARDS_PER_THREAD)
+  }
+
+  /// Creates a queue for `threads` threads, with `shards_per_thread` shards for
+  /// each. There are always at least two shards.
+  pub fn with_shards_per_thread(threads: usize, shards_per_thread: usize) -> Self {
+    let shards = threads.saturating_mul(shards_per_thread).max(2);
+    MultiQueue { shards: (0..shards).map(|_| Mutex::new(WeakHeap::new())).collect() }
+  }
+
+  /// Returns the number of shards.
+  pub fn shards(&self) -> usize {
+    self.shards.len()
+  }
+
+  /// Returns the number of elements in the queue. Shards are counted one at a
+  /// time, so under concurrent use the result may never have been exact.
+  pub fn len(&self) -> usize {
+    (0..self.shards.len()).map(|i| self.lock(i).len()).sum()
+  }
+
+  /// Returns `true` iff every shard was empty when visited.
+  pub fn is_empty(&self) -> bool {
+    (0..self.shards.len()).all(|i| self.lock(i).is_empty())
+  }
+
+  fn lock(&self, index: usize) -> MutexGuard<'_, WeakHeap<T>> {
+    self.shards[index].lock().unwrap_or_else(|e| self.recover(index, e.into_inner()))
+  }
+
+  fn try_lock(&self, index: usize) -> Option<MutexGuard<'_, WeakHeap<T>>> {
+    match self.shards[index].try_lock() {
+      Ok(guard) => Some(guard),
+      Err(TryLockError::Poisoned(e)) => Some(self.recover(index, e.into_inner())),
+      Err(TryLockError::WouldBlock) => None,
+    }
+  }
+
+  /// Discards the elements of a poisoned shard, which may be out of order, and
+  /// clears its poison.
+  fn recover<'a>(&'a self, index: usize, mut shard: MutexGuard<'a, WeakHeap<T>>) -> MutexGuard<'a, WeakHeap<T>> {
+    *shard = WeakHeap::new();
+    self.shards[index].clear_poison();
+    shard
+  }
+
+  /// Pushes `value` onto a random shard, trying others if it is locked.
+  pub fn push(&self, value: T) {
+    for _ in 0..self.shards.len() {
+      if let Some(mut shard) = self.try_lock(random_index(self.shards.len())) {
+        shard.push(value);
+        return;
+      }
+    }
+    self.lock(random_index(self.shards.len())).push(value);
+  }
+
+  /// Removes an element near the greatest from the queue and returns it, or
+  /// returns `None` if every shard was empty when visited.
+  pub fn pop(&self) -> Option<T> {
+    let n = self.shards.len();
+    for _ in 0..SAMPLES {
+      let i = random_index(n);
+      let j = (i + 1 + random_index(n - 1)) % n;
+      let (mut a, mut b) = match (self.try_lock(i), self.try_lock(j)) {
+        (Some(a), Some(b)) => (a, b),
+        // Another thread holds one of the shards; sample afresh rather than
+        // wait for it, but count the attempt so that a pop cannot spin.
+        _ => continue,
+      };
+      let better = if b.peek() > a.peek() { &mut b } else { &mut a };
+      if let Some(value) = better.pop() {
+        return Some(value);
+      }
+    }
+    // Every sample found a shard locked or both shards empty, so the queue may
+    // be heavily contended or nearly empty. Search every shard, waiting for
+    // each lock, rather than spin or report a spurious `None`.
+    let start = random_index(n);
+    (0..n).find_map(|k| self.lock((start + k) % n).pop())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeSet;
+  use std::sync::Arc;
+  use std::thread;
+  use super::{random_index, MultiQueue};
+
+  #[test]
+  fn random_indices_are_in_range_and_spread() {
+    let mut seen = [0; 7];
+    for _ in 0..7000 {
+      seen[random_index(7)] += 1;
+    }
+    assert!(seen.iter().all(|&count| count > 700), "{:?}", seen);
+  }
+
+  #[test]
+  fn rank_error_is_bounded_by_shards() {
+    let queue = MultiQueue::with_shards_per_thread(8, 2);
+    let n = 20_000;
+    for x in 0..n {
+      queue.push(x);
+    }
+    let mut remaining: BTreeSet<i32> = (0..n).collect();
+    let mut total_rank = 0;
+    let mut max_rank = 0;
+    while let Some(x) = queue.pop() {
+      let rank = remaining.range(x + 1..).count();
+      total_rank += rank;
+      max_rank = max_rank.max(rank);
+      assert!(remaining.remove(&x));
+    }
+    assert!(remaining.is_empty());
+    let mean_rank = total_rank as f64 / n as f64;
+    let shards = queue.shards() as f64;
+    assert!(mean_rank < 2.0 * shards, "mean rank {} with {} shards", mean_rank, shards);
+    assert!((max_rank as f64) < 8.0 * shards * shards.ln(), "max rank {} with {} shards", max_rank, shards);
+  }
+
+  #[test]
+  fn poisoned_shard_is_discarded() {
+    let queue = Arc::new(MultiQueue::new(1));
+    for x in 0..100 {
+      queue.push(x);
+    }
+    let in_first = queue.shards[0].lock().unwrap().len();
+    let poisoner = {
+      let queue = Arc::clone(&queue);
+      thread::spawn(move || {
+        let _shard = queue.shards[0].lock().unwrap();
+        panic!("poisoning a shard");
+      })
+    };
+    assert!(poisoner.join().is_err());
+    assert_eq!(queue.len(), 100 - in_first);
+    assert!(!queue.shards[0].is_poisoned());
+    queue.push(100);
+    queue.push(101);
+    let mut popped = 0;
+    while queue.pop().is_some() {
+      popped += 1;
+    }
+    assert_eq!(popped, 102 - in_first);
+  }
+
+  #[test]
+  fn concurrent_pushes_and_pops_lose_nothing() {
+    let threads = 8;
+    let per_thread = 10_000;
+    let queue = Arc::new(MultiQueue::new(threads));
+    let workers: Vec<_> = (0..threads).map(|t| {
+      let queue = Arc::clone(&queue);
+      thread::spawn(move || {
+        let mut popped = Vec::new();
+        for i in 0..per_thread {
+          queue.push(t * per_thread + i);
+          if i % 2 == 1 {
+            popped.extend(queue.pop());
+          }
+        }
+        popped
+      })
+    }).collect();
+    let mut popped: Vec<usize> = workers.into_iter().flat_map(|w| w.join().unwrap()).collect();
+    assert_eq!(queue.len(), threads * per_thread - popped.len());
+    popped.extend(std::iter::from_fn(|| queue.pop()));
+    popped.sort_unstable();
+    assert_eq!(popped, (0..threads * per_thread).collect::<Vec<_>>());
+    assert!(queue.is_empty());
+  }
+}
@@ -0,0 +1,429 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hooks for watching the individual steps a `WeakHeap` takes.
+//!
+//! A heap created with `WeakHeap::with_observer` reports each comparison,
+//! swap and valence flip it performs, and the start and end of each sift, to
+//! an `Observer`. Heaps created with `WeakHeap::new` use the `()` observer,
+//! whose methods are empty and compile away entirely.
+//!
+//! Two observers are provided: `Stats`, which counts steps and collects
+//! histograms of sift lengths, and `Recorder`, which captures the full
+//! sequence of steps.
+//!
+//! ```rust
+//! # use weak_heap::WeakHeap;
+//! # use weak_heap::observer::Stats;
+//! # fn main() {
+//! let mut heap = WeakHeap::with_observer(Stats::default());
+//! heap.extend(0..100);
+//! // Pushing ascending values always climbs all the way to the root.
+//! assert_eq!(heap.observer().sift_up_levels.count(), 100);
+//! assert_eq!(heap.observer().sift_up_levels.max(), Some(7));
+//! # }
+//! ```
+
+use std::fmt;
+#[cfg(feature = "stats")]
+use crate::stats::HeapStats;
+
+/// The kind of sift operation being reported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Sift {
+  /// A newly pushed element being moved towards the root.
+  Up,
+  /// The root being restored after a pop or a `PeekMut` modification, by
+  /// walking the special path of left children from offset 1.
+  Down,
+  /// The whole heap being rebuilt in place, as `retain` does after removing
+  /// elements. Every valence bit that is set is cleared, and then each element
+  /// is joined with its distinguished ancestor, starting from the end of the
+  /// heap. Reported as starting at offset 0.
+  Rebuild,
+}
+
+/// Receives notifications of the steps taken by a `WeakHeap`. All offsets are
+/// indices into the heap's storage, with the root at offset 0. Every method
+/// does nothing by default.
+///
+/// Replaying the steps reported between `sift_start` and `sift_end` against
+/// the storage layout reproduces the heap's state: a `Sift::Up` begins with a
+/// new element at the given offset, a `Sift::Down` begins just after the last
+/// element has been moved to the root, and a `Sift::Rebuild` begins just after
+/// `retain` has removed elements from storage, keeping the rest in order.
+pub trait Observer {
+  /// The elements at offsets `a` and `b` were compared.
+  fn compare(&mut self, _a: usize, _b: usize) {}
+
+  /// The elements at offsets `a` and `b` were exchanged.
+  fn swap(&mut self, _a: usize, _b: usize) {}
+
+  /// The valence bit of the element at `offset` changed to `valence`,
+  /// exchanging its left and right subtrees.
+  fn flip(&mut self, _offset: usize, _valence: bool) {}
+
+  /// A sift of kind `sift` is starting at `offset`.
+  fn sift_start(&mut self, _sift: Sift, _offset: usize) {}
+
+  /// The sift most recently started has finished.
+  fn sift_end(&mut self, _sift: Sift) {}
+}
+
+/// The observer used by default, which ignores everything.
+impl Observer for () {}
+
+impl<O: Observer + ?Sized> Observer for &mut O {
+  fn compare(&mut self, a: usize, b: usize) {
+    (**self).compare(a, b)
+  }
+
+  fn swap(&mut self, a: usize, b: usize) {
+    (**self).swap(a, b)
+  }
+
+  fn flip(&mut self, offset: usize, valence: bool) {
+    (**self).flip(offset, valence)
+  }
+
+  fn sift_start(&mut self, sift: Sift, offset: usize) {
+    (**self).sift_start(sift, offset)
+  }
+
+  fn sift_end(&mut self, sift: Sift) {
+    (**self).sift_end(sift)
+  }
+}
+
+/// A histogram of small non-negative integers.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Histogram {
+  buckets: Vec<u64>,
+}
+
+impl Histogram {
+  /// Records one occurrence of `value`.
+  pub fn record(&mut self, value: usize) {
+    if self.buckets.len() <= value {
+      self.buckets.resize(value + 1, 0);
+    }
+    self.buckets[value] += 1;
+  }
+
+  /// Returns the number of occurrences of each value, indexed by value.
+  pub fn buckets(&self) -> &[u64] {
+    &self.buckets
+  }
+
+  /// Returns the number of values recorded.
+  pub fn count(&self) -> u64 {
+    self.buckets.iter().sum()
+  }
+
+  /// Returns the largest value recorded, or `None` if nothing has been.
+  pub fn max(&self) -> Option<usize> {
+    self.buckets.iter().rposition(|n| *n > 0)
+  }
+
+  /// Returns the mean of the values recorded, or `None` if nothing has been.
+  pub fn mean(&self) -> Option<f64> {
+    let count = self.count();
+    if count == 0 {
+      return None;
+    }
+    let sum: u64 = self.buckets.iter().enumerate().map(|(value, n)| value as u64 * n).sum();
+    Some(sum as f64 / count as f64)
+  }
+}
+
+impl fmt::Display for Histogram {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let widest = self.buckets.iter().copied().max().unwrap_or(0);
+    for (value, n) in self.buckets.iter().enumerate() {
+      let bar = if widest == 0 { 0 } else { (n * 40).div_ceil(widest) };
+      writeln!(f, "{:>4} {:>10} {}", value, n, "#".repeat(bar as usize))?;
+    }
+    Ok(())
+  }
+}
+
+/// An observer that counts steps and collects histograms of sift lengths.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+  /// The number of comparisons made.
+  pub comparisons: u64,
+  /// The number of swaps made.
+  pub swaps: u64,
+  /// The number of valence bits changed.
+  pub flips: u64,
+  /// For each sift up, the number of levels the element climbed in the
+  /// multi-way tree.
+  pub sift_up_levels: Histogram,
+  /// For each sift down, the length of the special path walked.
+  pub sift_down_path: Histogram,
+  /// Swaps made since the current sift started.
+  sift_swaps: usize,
+  /// Comparisons made since the current sift started.
+  sift_comparisons: usize,
+}
+
+impl Stats {
+  /// Clears all counts and histograms.
+  pub fn reset(&mut self) {
+    *self = Stats::default();
+  }
+}
+
+impl Observer for Stats {
+  fn compare(&mut self, _a: usize, _b: usize) {
+    self.comparisons += 1;
+    self.sift_comparisons += 1;
+  }
+
+  fn swap(&mut self, _a: usize, _b: usize) {
+    self.swaps += 1;
+    self.sift_swaps += 1;
+  }
+
+  fn flip(&mut self, _offset: usize, _valence: bool) {
+    self.flips += 1;
+  }
+
+  fn sift_start(&mut self, _sift: Sift, _offset: usize) {
+    self.sift_swaps = 0;
+    self.sift_comparisons = 0;
+  }
+
+  fn sift_end(&mut self, sift: Sift) {
+    match sift {
+      Sift::Up => self.sift_up_levels.record(self.sift_swaps),
+      // Each element on the special path is compared against the root once.
+      Sift::Down => self.sift_down_path.record(self.sift_comparisons),
+      Sift::Rebuild => (),
+    }
+  }
+}
+
+/// A single step reported to an `Observer`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Step {
+  Compare(usize, usize),
+  Swap(usize, usize),
+  Flip(usize, bool),
+  SiftStart(Sift, usize),
+  SiftEnd(Sift),
+}
+
+/// An observer that records every step in order, for example to drive an
+/// animation of the heap.
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+  /// The steps recorded so far.
+  pub steps: Vec<Step>,
+}
+
+impl Recorder {
+  /// Returns the steps recorded so far and clears them.
+  pub fn take(&mut self) -> Vec<Step> {
+    std::mem::take(&mut self.steps)
+  }
+}
+
+impl Observer for Recorder {
+  fn compare(&mut self, a: usize, b: usize) {
+    self.steps.push(Step::Compare(a, b));
+  }
+
+  fn swap(&mut self, a: usize, b: usize) {
+    self.steps.push(Step::Swap(a, b));
+  }
+
+  fn flip(&mut self, offset: usize, valence: bool) {
+    self.steps.push(Step::Flip(offset, valence));
+  }
+
+  fn sift_start(&mut self, sift: Sift, offset: usize) {
+    self.steps.push(Step::SiftStart(sift, offset));
+  }
+
+  fn sift_end(&mut self, sift: Sift) {
+    self.steps.push(Step::SiftEnd(sift));
+  }
+}
+
+/// The observer of a `WeakHeap`, together with the heap's own counters when
+/// the `stats` feature is enabled. The heap reports every step through this.
+#[derive(Debug)]
+pub(crate) struct Hooks<O: Observer> {
+  pub(crate) observer: O,
+  #[cfg(feature = "stats")]
+  pub(crate) stats: HeapStats,
+}
+
+impl<O: Observer> Hooks<O> {
+  pub(crate) fn new(observer: O) -> Self {
+    Hooks {
+      observer,
+      #[cfg(feature = "stats")]
+      stats: HeapStats::default(),
+    }
+  }
+
+  /// An element was pushed, leaving the heap with `len` elements.
+  pub(crate) fn pushed(&mut self, _len: usize) {
+    #[cfg(feature = "stats")]
+    {
+      self.stats.pushes += 1;
+      self.stats.max_len = self.stats.max_len.max(_len);
+    }
+  }
+
+  /// An element was popped.
+  pub(crate) fn popped(&mut self) {
+    #[cfg(feature = "stats")]
+    {
+      self.stats.pops += 1;
+    }
+  }
+
+  /// `count` elements were written to new positions in storage.
+  pub(crate) fn moved(&mut self, _count: u64) {
+    #[cfg(feature = "stats")]
+    {
+      self.stats.moves += _count;
+    }
+  }
+
+  /// The heap was built in place from `len` elements.
+  pub(crate) fn rebuilt(&mut self, _len: usize) {
+    #[cfg(feature = "stats")]
+    {
+      self.stats.max_len = self.stats.max_len.max(_len);
+    }
+  }
+}
+
+impl<O: Observer> Observer for Hooks<O> {
+  fn compare(&mut self, a: usize, b: usize) {
+    #[cfg(feature = "stats")]
+    {
+      self.stats.comparisons += 1;
+    }
+    self.observer.compare(a, b)
+  }
+
+  fn swap(&mut self, a: usize, b: usize) {
+    self.observer.swap(a, b)
+  }
+
+  fn flip(&mut self, offset: usize, valence: bool) {
+    #[cfg(feature = "stats")]
+    {
+      self.stats.flips += 1;
+    }
+    self.observer.flip(offset, valence)
+  }
+
+  fn sift_start(&mut self, sift: Sift, offset: usize) {
+    self.observer.sift_start(sift, offset)
+  }
+
+  fn sift_end(&mut self, sift: Sift) {
+    self.observer.sift_end(sift)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Recorder, Sift, Stats, Step};
+  use crate::WeakHeap;
+
+  #[test]
+  fn recorded_steps_replay() {
+    let mut heap = WeakHeap::with_observer(Recorder::default());
+    let mut values = Vec::new();
+    let mut valences = Vec::new();
+    for i in 0..200 {
+      if i % 50 == 49 {
+        heap.retain(|x| x % 4 != 0);
+        let kept: Vec<bool> = values.iter().map(|x| x % 4 != 0).collect();
+        let mut k = kept.iter();
+        values.retain(|_| *k.next().unwrap());
+        let mut k = kept.iter();
+        valences.retain(|_| *k.next().unwrap());
+      } else if i % 3 == 2 {
+        heap.pop();
+        values.swap_remove(0);
+        valences.swap_remove(0);
+      } else {
+        let x = (i * 7919) % 101;
+        heap.push(x);
+        values.push(x);
+        valences.push(false);
+      }
+      for step in heap.observer_mut().take() {
+        match step {
+          Step::Swap(a, b) => values.swap(a, b),
+          Step::Flip(offset, valence) => valences[offset] = valence,
+          _ => (),
+        }
+      }
+      let tree = heap.tree();
+      assert_eq!(tree.nodes().map(|n| *n.value()).collect::<Vec<_>>(), values);
+      assert!(tree.nodes().all(|n| n.valence() == valences[n.offset()]));
+    }
+  }
+
+  #[test]
+  fn sifts_are_bracketed() {
+    let mut heap = WeakHeap::with_observer(Recorder::default());
+    heap.extend(vec![3, 1, 4, 1, 5]);
+    heap.pop();
+    let mut open = None;
+    for step in &heap.observer().steps {
+      match *step {
+        Step::SiftStart(sift, _) => {
+          assert_eq!(open, None);
+          open = Some(sift);
+        },
+        Step::SiftEnd(sift) => assert_eq!(open.take(), Some(sift)),
+        Step::Compare(..) | Step::Swap(..) => assert!(open.is_some()),
+        Step::Flip(..) => (),
+      }
+    }
+    assert_eq!(open, None);
+    assert_eq!(heap.observer().steps.last(), Some(&Step::SiftEnd(Sift::Down)));
+  }
+
+  #[test]
+  fn stats_histograms() {
+    let mut stats = Stats::default();
+    {
+      let mut heap = WeakHeap::with_observer(&mut stats);
+      heap.extend((0..64).rev());
+      while heap.pop().is_some() {}
+    }
+    // Descending pushes never move.
+    assert_eq!(stats.sift_up_levels.buckets(), &[64]);
+    assert_eq!(stats.sift_down_path.count(), 63);
+    assert_eq!(stats.sift_down_path.max(), Some(6));
+    let path_comparisons: u64 = stats.sift_down_path.buckets().iter().enumerate()
+      .map(|(len, n)| len as u64 * n)
+      .sum();
+    assert_eq!(stats.comparisons, 63 + path_comparisons);
+    stats.reset();
+    assert_eq!(stats.comparisons, 0);
+    assert_eq!(stats.sift_down_path.mean(), None);
+  }
+}
@@ -0,0 +1,587 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A weak heap that holds more elements than fit in memory.
+//!
+//! `PagedWeakHeap` uses the same layout as `WeakHeap`, but only the top
+//! `resident_levels` levels of the binary tree are always held in memory. The
+//! remaining elements are stored in a backing file as fixed-size records, each
+//! a valence byte followed by the element's `Codec` encoding, and are read and
+//! written a page of consecutive records at a time.
+//!
+//! Every operation walks a path between the root and the bottom of the heap,
+//! so the shallow levels are touched far more often than the deep ones. When
+//! the page cache is full, the page from the deepest level is evicted first,
+//! and the least recently used page among those from that level.
+//!
+//! ```rust
+//! # use std::io::Cursor;
+//! # use weak_heap::paged::{PagedConfig, PagedWeakHeap};
+//! # fn main() {
+//! let config = PagedConfig { resident_levels: 4, page_records: 8, cache_pages: 2, };
+//! let mut heap = PagedWeakHeap::with_storage(Cursor::new(Vec::new()), config);
+//! for x in 0..1000u32 {
+//!   heap.push(x * 7 % 1000).unwrap();
+//! }
+//! assert_eq!(heap.peek(), Some(&999));
+//! assert_eq!(heap.pop().unwrap(), Some(999));
+//! assert_eq!(heap.pop().unwrap(), Some(998));
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+use crate::HeapEntry;
+use crate::codec::Codec;
+
+/// The layout and caching parameters of a `PagedWeakHeap`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PagedConfig {
+  /// The number of levels of the binary tree, counting the root, that are
+  /// always held in memory. The top `2^(resident_levels - 1)` elements are
+  /// resident.
+  pub resident_levels: u32,
+  /// The number of records in each page. Must be a power of two no greater
+  /// than the number of resident elements.
+  pub page_records: usize,
+  /// The maximum number of pages held in memory at once. Must be at least 1.
+  pub cache_pages: usize,
+}
+
+impl Default for PagedConfig {
+  /// Keeps 2^16 elements resident, with 64 pages of 4096 records cached.
+  fn default() -> Self {
+    PagedConfig {
+      resident_levels: 17,
+      page_records: 4096,
+      cache_pages: 64,
+    }
+  }
+}
+
+/// A page of records from the backing file held in memory.
+#[derive(Debug)]
+struct Page<T: fmt::Debug> {
+  /// The entries of the page that are within the heap.
+  entries: Vec<HeapEntry<T>>,
+  /// Whether the page has been modified since it was read.
+  dirty: bool,
+  /// The value of the heap's clock when the page was last accessed.
+  last_used: u64,
+}
+
+/// A max-heap whose lower levels are stored in a file. See the module
+/// documentation for details.
+///
+/// Operations that may need to read or write pages return `io::Result`;
+/// otherwise the API mirrors `WeakHeap`.
+///
+/// An operation is not undone if it fails part way through, which may leave
+/// the heap's length, ordering or cached pages inconsistent. Instead, the first
+/// I/O error poisons the heap: every later `push`, `pop` or `flush` fails
+/// without touching storage, and `len` and `peek` are no longer meaningful.
+#[derive(Debug)]
+pub struct PagedWeakHeap<T: fmt::Debug + Ord + Codec + Clone, S: Read + Write + Seek = File> {
+  /// The elements at offsets below `resident_len`.
+  top: Vec<HeapEntry<T>>,
+  resident_len: usize,
+  len: usize,
+  page_records: usize,
+  cache_pages: usize,
+  /// Cached pages, by page number. Page `k` holds the elements at offsets
+  /// `resident_len + k * page_records` onwards.
+  pages: HashMap<usize, Page<T>>,
+  clock: u64,
+  storage: S,
+  page_reads: u64,
+  page_writes: u64,
+  /// Whether an operation has failed, leaving the heap inconsistent.
+  poisoned: bool,
+}
+
+impl<T: fmt::Debug + Ord + Codec + Clone> PagedWeakHeap<T> {
+  /// Creates a new heap backed by the file at `path`, which is created if
+  /// necessary and truncated.
+  pub fn create<P: AsRef<Path>>(path: P, config: PagedConfig) -> io::Result<Self> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+    Ok(PagedWeakHeap::with_storage(file, config))
+  }
+}
+
+impl<T: fmt::Debug + Ord + Codec + Clone, S: Read + Write + Seek> PagedWeakHeap<T, S> {
+  /// Creates a new heap backed by `storage`, whose existing contents are
+  /// ignored and overwritten.
+  ///
+  /// Panics if `config` is invalid.
+  pub fn with_storage(storage: S, config: PagedConfig) -> Self {
+    assert!(config.resident_levels >= 1 && config.resident_levels < usize::BITS,
+            "resident_levels must be between 1 and {}", usize::BITS - 1);
+    let resident_len = 1 << (config.resident_levels - 1);
+    assert!(config.page_records.is_power_of_two() && config.page_records <= resident_len,
+            "page_records must be a power of two no greater than {}", resident_len);
+    assert!(config.cache_pages >= 1, "cache_pages must be at least 1");
+    PagedWeakHeap {
+      top: Vec::new(),
+      resident_len,
+      len: 0,
+      page_records: config.page_records,
+      cache_pages: config.cache_pages,
+      pages: HashMap::new(),
+      clock: 0,
+      storage,
+      page_reads: 0,
+      page_writes: 0,
+      poisoned: false,
+    }
+  }
+
+  /// Returns the number of elements in the heap.
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  /// Returns `true` iff the heap is empty.
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// Returns a reference to the top element on the heap, or `None` if the heap
+  /// is empty. The top element is always resident, so this never does I/O.
+  pub fn peek(&self) -> Option<&T> {
+    self.top.first().map(|x| &x.value)
+  }
+
+  /// Returns `true` iff an earlier operation failed with an I/O error. See the
+  /// type documentation.
+  pub fn is_poisoned(&self) -> bool {
+    self.poisoned
+  }
+
+  /// Pushes `value` onto the heap.
+  pub fn push(&mut self, value: T) -> io::Result<()> {
+    self.unless_poisoned(|heap| heap.push_unchecked(value))
+  }
+
+  fn push_unchecked(&mut self, value: T) -> io::Result<()> {
+    let offset = self.len;
+    let entry = HeapEntry { valence: false, value, };
+    if offset < self.resident_len {
+      self.top.push(entry);
+    } else {
+      let page = self.page_mut(self.page_number(offset))?;
+      page.entries.push(entry);
+      page.dirty = true;
+    }
+    self.len += 1;
+    if offset & 1 == 0 {
+      self.entry_mut(offset / 2)?.valence = true;
+    }
+    self.sift_up(offset)
+  }
+
+  /// Removes the top element from the heap and returns it, or returns `None` if
+  /// the heap is empty.
+  pub fn pop(&mut self) -> io::Result<Option<T>> {
+    self.unless_poisoned(Self::pop_unchecked)
+  }
+
+  fn pop_unchecked(&mut self) -> io::Result<Option<T>> {
+    if self.len <= 1 {
+      self.len = 0;
+      return Ok(self.top.pop().map(|entry| entry.value));
+    }
+    let last = self.len - 1;
+    let last = if last < self.resident_len {
+      self.top.pop()
+    } else {
+      let number = self.page_number(last);
+      let page = self.page_mut(number)?;
+      let entry = page.entries.pop();
+      if page.entries.is_empty() {
+        self.pages.remove(&number);
+      }
+      entry
+    }.expect("heap length out of sync with storage");
+    self.len -= 1;
+    let result = mem::replace(&mut self.top[0].value, last.value);
+    self.top[0].valence = false;
+    self.sift_down()?;
+    Ok(Some(result))
+  }
+
+  /// Writes every modified cached page to the backing storage and flushes it.
+  pub fn flush(&mut self) -> io::Result<()> {
+    self.unless_poisoned(Self::flush_unchecked)
+  }
+
+  fn flush_unchecked(&mut self) -> io::Result<()> {
+    let mut dirty: Vec<usize> = self.pages.iter()
+      .filter(|(_, page)| page.dirty)
+      .map(|(number, _)| *number)
+      .collect();
+    dirty.sort_unstable();
+    for number in dirty {
+      self.write_page(number)?;
+    }
+    self.storage.flush()
+  }
+
+  /// Returns the number of pages read from the backing storage so far.
+  pub fn page_reads(&self) -> u64 {
+    self.page_reads
+  }
+
+  /// Returns the number of pages written to the backing storage so far.
+  pub fn page_writes(&self) -> u64 {
+    self.page_writes
+  }
+
+  /// Runs `op` unless the heap is poisoned, and poisons it if `op` fails.
+  fn unless_poisoned<R, F>(&mut self, op: F) -> io::Result<R>
+  where F: FnOnce(&mut Self) -> io::Result<R> {
+    if self.poisoned {
+      return Err(io::Error::other("heap is poisoned by an earlier I/O error"));
+    }
+    let result = op(self);
+    if result.is_err() {
+      self.poisoned = true;
+    }
+    result
+  }
+
+  /// Returns the size in bytes of each record in the backing storage.
+  fn record_size() -> usize {
+    1 + T::SIZE
+  }
+
+  /// Returns the number of the page that holds the element at `offset`, which
+  /// must not be resident.
+  fn page_number(&self, offset: usize) -> usize {
+    (offset - self.resident_len) / self.page_records
+  }
+
+  /// Returns the level of the binary tree that page `number` lies in. Pages
+  /// never span levels, since every paged level is a multiple of the page size.
+  fn page_level(&self, number: usize) -> u32 {
+    let offset = self.resident_len + number * self.page_records;
+    usize::BITS - offset.leading_zeros()
+  }
+
+  /// Returns the cached page `number`, reading it from storage first if
+  /// necessary.
+  fn page_mut(&mut self, number: usize) -> io::Result<&mut Page<T>> {
+    self.clock += 1;
+    if !self.pages.contains_key(&number) {
+      if self.pages.len() >= self.cache_pages {
+        self.evict()?;
+      }
+      let page = self.read_page(number)?;
+      self.pages.insert(number, page);
+    }
+    let page = self.pages.get_mut(&number).unwrap();
+    page.last_used = self.clock;
+    Ok(page)
+  }
+
+  /// Evicts the page from the deepest level, breaking ties by least recent
+  /// use.
+  fn evict(&mut self) -> io::Result<()> {
+    let victim = self.pages.iter()
+      .map(|(number, page)| (self.page_level(*number), u64::MAX - page.last_used, *number))
+      .max()
+      .map(|(_, _, number)| number);
+    if let Some(number) = victim {
+      if self.pages[&number].dirty {
+        self.write_page(number)?;
+      }
+      self.pages.remove(&number);
+    }
+    Ok(())
+  }
+
+  /// Reads the entries of page `number` that are within the heap.
+  fn read_page(&mut self, number: usize) -> io::Result<Page<T>> {
+    let start = self.resident_len + number * self.page_records;
+    let count = self.len.saturating_sub(start).min(self.page_records);
+    let mut entries = Vec::with_capacity(self.page_records);
+    if count > 0 {
+      let mut buf = vec![0; count * Self::record_size()];
+      self.storage.seek(SeekFrom::Start((number * self.page_records * Self::record_size()) as u64))?;
+      self.storage.read_exact(&mut buf)?;
+      self.page_reads += 1;
+      for record in buf.chunks_exact(Self::record_size()) {
+        let valence = match record[0] {
+          0 => false,
+          1 => true,
+          b => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                         format!("invalid valence byte {}", b))),
+        };
+        entries.push(HeapEntry { valence, value: T::decode(&record[1..])?, });
+      }
+    }
+    Ok(Page { entries, dirty: false, last_used: self.clock, })
+  }
+
+  /// Writes cached page `number` to storage and marks it clean.
+  fn write_page(&mut self, number: usize) -> io::Result<()> {
+    let page = self.pages.get_mut(&number).unwrap();
+    let mut buf = vec![0; page.entries.len() * Self::record_size()];
+    for (entry, record) in page.entries.iter().zip(buf.chunks_exact_mut(Self::record_size())) {
+      record[0] = entry.valence as u8;
+      entry.value.encode(&mut record[1..]);
+    }
+    page.dirty = false;
+    self.storage.seek(SeekFrom::Start((number * self.page_records * Self::record_size()) as u64))?;
+    self.storage.write_all(&buf)?;
+    self.page_writes += 1;
+    Ok(())
+  }
+
+  /// Returns the entry at `offset`.
+  fn entry(&mut self, offset: usize) -> io::Result<&HeapEntry<T>> {
+    if offset < self.resident_len {
+      return Ok(&self.top[offset]);
+    }
+    let index = (offset - self.resident_len) % self.page_records;
+    let page = self.page_mut(self.page_number(offset))?;
+    Ok(&page.entries[index])
+  }
+
+  /// Returns the entry at `offset` for modification.
+  fn entry_mut(&mut self, offset: usize) -> io::Result<&mut HeapEntry<T>> {
+    if offset < self.resident_len {
+      return Ok(&mut self.top[offset]);
+    }
+    let index = (offset - self.resident_len) % self.page_records;
+    let page = self.page_mut(self.page_number(offset))?;
+    page.dirty = true;
+    Ok(&mut page.entries[index])
+  }
+
+  /// Returns the offset of the distinguished ancestor of the element at
+  /// `offset`. See `WeakHeap::distinguished_ancestor_offset`.
+  fn distinguished_ancestor_offset(&mut self, mut offset: usize) -> io::Result<usize> {
+    while (offset & 1) == (self.entry(offset / 2)?.valence as usize) {
+      offset /= 2;
+    }
+    Ok(offset / 2)
+  }
+
+  /// Sifts the element at `offset` up. See `WeakHeap::sift_up`.
+  fn sift_up(&mut self, mut offset: usize) -> io::Result<()> {
+    let element = self.entry(offset)?.value.clone();
+    while offset > 0 {
+      let ancestor_offset = self.distinguished_ancestor_offset(offset)?;
+      let ancestor_value = self.entry(ancestor_offset)?.value.clone();
+      if ancestor_value >= element {
+        break;
+      }
+      let entry = self.entry_mut(offset)?;
+      entry.value = ancestor_value;
+      entry.valence = !entry.valence;
+      offset = ancestor_offset;
+    }
+    self.entry_mut(offset)?.value = element;
+    Ok(())
+  }
+
+  /// Sifts the top of the heap down. See `WeakHeap::sift_down`; this walks the
+  /// same special path iteratively.
+  fn sift_down(&mut self) -> io::Result<()> {
+    if self.len < 2 {
+      return Ok(());
+    }
+    let mut offset = 1;
+    loop {
+      let child = 2 * offset + self.entry(offset)?.valence as usize;
+      if child >= self.len {
+        break;
+      }
+      offset = child;
+    }
+    while offset > 0 {
+      let value = self.entry(offset)?.value.clone();
+      if self.top[0].value < value {
+        let old = mem::replace(&mut self.top[0].value, value);
+        let entry = self.entry_mut(offset)?;
+        entry.value = old;
+        entry.valence = !entry.valence;
+      }
+      offset /= 2;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BinaryHeap;
+  use std::env;
+  use std::fs;
+  use std::cell::Cell;
+  use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+  use std::rc::Rc;
+  use super::{PagedConfig, PagedWeakHeap};
+
+  /// In-memory storage that fails every operation while `fail` is set.
+  struct Flaky {
+    inner: Cursor<Vec<u8>>,
+    fail: Rc<Cell<bool>>,
+  }
+
+  impl Flaky {
+    fn check(&self) -> io::Result<()> {
+      if self.fail.get() {
+        Err(io::Error::other("storage failed"))
+      } else {
+        Ok(())
+      }
+    }
+  }
+
+  impl Read for Flaky {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.check()?;
+      self.inner.read(buf)
+    }
+  }
+
+  impl Write for Flaky {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.check()?;
+      self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      self.check()
+    }
+  }
+
+  impl Seek for Flaky {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+      self.check()?;
+      self.inner.seek(pos)
+    }
+  }
+
+  fn small() -> PagedConfig {
+    PagedConfig { resident_levels: 3, page_records: 2, cache_pages: 3, }
+  }
+
+  #[test]
+  fn matches_binary_heap() {
+    let mut heap = PagedWeakHeap::with_storage(Cursor::new(Vec::new()), small());
+    let mut model = BinaryHeap::new();
+    for i in 0..3000u32 {
+      if i % 5 == 3 || i % 7 == 6 {
+        assert_eq!(heap.pop().unwrap(), model.pop());
+      } else {
+        let x = i.wrapping_mul(2_654_435_761) % 1000;
+        heap.push(x).unwrap();
+        model.push(x);
+      }
+      assert_eq!(heap.len(), model.len());
+      assert_eq!(heap.peek(), model.peek());
+    }
+    while let Some(x) = model.pop() {
+      assert_eq!(heap.pop().unwrap(), Some(x));
+    }
+    assert_eq!(heap.pop().unwrap(), None);
+    assert!(heap.page_reads() > 0);
+    assert!(heap.page_writes() > 0);
+  }
+
+  #[test]
+  fn evicts_deepest_level_first() {
+    let config = PagedConfig { resident_levels: 2, page_records: 2, cache_pages: 2, };
+    let mut heap = PagedWeakHeap::with_storage(Cursor::new(Vec::new()), config);
+    for x in (0..64u16).rev() {
+      heap.push(x).unwrap();
+    }
+    heap.flush().unwrap();
+    heap.pages.clear();
+    let cached = |heap: &PagedWeakHeap<u16, _>| {
+      let mut pages: Vec<usize> = heap.pages.keys().copied().collect();
+      pages.sort_unstable();
+      pages
+    };
+    // Pages 0, 1, 5 and 9 hold levels 2, 3, 4 and 5 respectively.
+    heap.page_mut(0).unwrap();
+    heap.page_mut(5).unwrap();
+    heap.page_mut(9).unwrap();
+    assert_eq!(cached(&heap), vec![0, 9]);
+    heap.page_mut(1).unwrap();
+    assert_eq!(cached(&heap), vec![0, 1]);
+    heap.page_mut(0).unwrap();
+    heap.page_mut(2).unwrap();
+    assert_eq!(cached(&heap), vec![0, 2]);
+    // Pages 1 and 2 are both in level 3, so the least recently used goes.
+    heap.pages.remove(&0);
+    heap.page_mut(1).unwrap();
+    heap.page_mut(2).unwrap();
+    heap.page_mut(1).unwrap();
+    heap.page_mut(5).unwrap();
+    assert_eq!(cached(&heap), vec![1, 5]);
+    for x in (0..64).rev() {
+      assert_eq!(heap.pop().unwrap(), Some(x));
+    }
+  }
+
+  #[test]
+  fn file_backed() {
+    let path = env::temp_dir().join(format!("weak-heap-paged-{}", std::process::id()));
+    {
+      let mut heap = PagedWeakHeap::create(&path, small()).unwrap();
+      for x in 0..500u64 {
+        heap.push(x * 31 % 500).unwrap();
+      }
+      heap.flush().unwrap();
+      assert!(fs::metadata(&path).unwrap().len() > 0);
+      for x in (0..500).rev() {
+        assert_eq!(heap.pop().unwrap(), Some(x));
+      }
+    }
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn io_error_poisons() {
+    let fail = Rc::new(Cell::new(false));
+    let storage = Flaky { inner: Cursor::new(Vec::new()), fail: Rc::clone(&fail), };
+    let mut heap = PagedWeakHeap::with_storage(storage, small());
+    for x in 0..100u32 {
+      heap.push(x).unwrap();
+    }
+    assert!(!heap.is_poisoned());
+    fail.set(true);
+    // Pushing the maximum sifts it through evicted pages and fails part way.
+    assert!(heap.push(1000).is_err());
+    assert!(heap.is_poisoned());
+    fail.set(false);
+    assert!(heap.push(0).is_err());
+    assert!(heap.pop().is_err());
+    assert!(heap.flush().is_err());
+  }
+
+  #[test]
+  #[should_panic(expected = "page_records must be a power of two")]
+  fn rejects_misaligned_pages() {
+    let config = PagedConfig { resident_levels: 2, page_records: 4, cache_pages: 1, };
+    PagedWeakHeap::<u8, _>::with_storage(Cursor::new(Vec::new()), config);
+  }
+}
@@ -0,0 +1,383 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Traits abstracting over priority queue implementations.
+
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::io::{self, Read, Seek, Write};
+use crate::{Observer, WeakHeap};
+use crate::async_queue::AsyncWeakHeap;
+use crate::codec::Codec;
+use crate::multiqueue::MultiQueue;
+use crate::paged::PagedWeakHeap;
+use crate::sync::SyncWeakHeap;
+
+/// A max-priority queue: elements are popped in descending order.
+///
+/// This is implemented by the heaps that are owned by a single thread and
+/// cannot fail: `WeakHeap` and `StdBinaryHeap`. The other heap variants in the
+/// crate implement one of two companion traits instead:
+/// `FalliblePriorityQueue`, for `paged::PagedWeakHeap`, whose pushes and pops
+/// perform I/O, and `SharedPriorityQueue`, for `sync::SyncWeakHeap`,
+/// `async_queue::AsyncWeakHeap` and `multiqueue::MultiQueue`, which are shared
+/// between threads.
+///
+/// `delay_queue::DelayQueue` implements none of them. Its items are ordered by
+/// deadline rather than by their own `Ord`, and whether one may be removed
+/// depends on the clock, so it is not a priority queue over its items.
+///
+/// ```rust
+/// # use weak_heap::{PriorityQueue, StdBinaryHeap, WeakHeap};
+/// fn drain<Q: PriorityQueue>(mut queue: Q) -> Vec<Q::Item> {
+///   let mut items = Vec::with_capacity(queue.len());
+///   while let Some(x) = queue.pop() {
+///     items.push(x);
+///   }
+///   items
+/// }
+///
+/// # fn main() {
+/// let mut weak = WeakHeap::new();
+/// let mut binary = StdBinaryHeap::new();
+/// for x in &[3, 1, 2] {
+///   weak.push(*x);
+///   binary.push(*x);
+/// }
+/// assert_eq!(drain(weak), drain(binary));
+/// # }
+/// ```
+pub trait PriorityQueue {
+  /// The type of the elements in the queue.
+  type Item;
+
+  /// Pushes `item` onto the queue.
+  fn push(&mut self, item: Self::Item);
+
+  /// Removes the greatest element from the queue and returns it, or returns
+  /// `None` if the queue is empty.
+  fn pop(&mut self) -> Option<Self::Item>;
+
+  /// Returns a reference to the greatest element in the queue, or `None` if the
+  /// queue is empty.
+  fn peek(&self) -> Option<&Self::Item>;
+
+  /// Returns the number of elements in the queue.
+  fn len(&self) -> usize;
+
+  /// Returns `true` iff the queue is empty.
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl<T: fmt::Debug + Ord, O: Observer> PriorityQueue for WeakHeap<T, O> {
+  type Item = T;
+
+  fn push(&mut self, item: T) {
+    WeakHeap::push(self, item)
+  }
+
+  fn pop(&mut self) -> Option<T> {
+    WeakHeap::pop(self)
+  }
+
+  fn peek(&self) -> Option<&T> {
+    WeakHeap::peek(self)
+  }
+
+  fn len(&self) -> usize {
+    WeakHeap::len(self)
+  }
+
+  fn is_empty(&self) -> bool {
+    WeakHeap::is_empty(self)
+  }
+}
+
+/// Wraps a `std::collections::BinaryHeap` so that it implements
+/// `PriorityQueue`.
+#[derive(Clone, Debug)]
+pub struct StdBinaryHeap<T: Ord>(pub BinaryHeap<T>);
+
+impl<T: Ord> Default for StdBinaryHeap<T> {
+  fn default() -> Self {
+    StdBinaryHeap::new()
+  }
+}
+
+impl<T: Ord> StdBinaryHeap<T> {
+  /// Creates a new, empty heap.
+  pub fn new() -> Self {
+    StdBinaryHeap(BinaryHeap::new())
+  }
+
+  /// Creates a new heap with capacity for at least `cap` elements.
+  pub fn with_capacity(cap: usize) -> Self {
+    StdBinaryHeap(BinaryHeap::with_capacity(cap))
+  }
+
+  /// Returns the wrapped heap.
+  pub fn into_inner(self) -> BinaryHeap<T> {
+    self.0
+  }
+}
+
+impl<T: Ord> From<BinaryHeap<T>> for StdBinaryHeap<T> {
+  fn from(heap: BinaryHeap<T>) -> Self {
+    StdBinaryHeap(heap)
+  }
+}
+
+impl<T: Ord> PriorityQueue for StdBinaryHeap<T> {
+  type Item = T;
+
+  fn push(&mut self, item: T) {
+    self.0.push(item)
+  }
+
+  fn pop(&mut self) -> Option<T> {
+    self.0.pop()
+  }
+
+  fn peek(&self) -> Option<&T> {
+    self.0.peek()
+  }
+
+  fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+}
+
+/// A max-priority queue whose pushes and pops may fail, such as one that keeps
+/// its elements in storage.
+pub trait FalliblePriorityQueue {
+  /// The type of the elements in the queue.
+  type Item;
+  /// The type of the errors that pushes and pops may return.
+  type Error;
+
+  /// Pushes `item` onto the queue.
+  fn push(&mut self, item: Self::Item) -> Result<(), Self::Error>;
+
+  /// Removes the greatest element from the queue and returns it, or returns
+  /// `None` if the queue is empty.
+  fn pop(&mut self) -> Result<Option<Self::Item>, Self::Error>;
+
+  /// Returns a reference to the greatest element in the queue, or `None` if the
+  /// queue is empty.
+  fn peek(&self) -> Option<&Self::Item>;
+
+  /// Returns the number of elements in the queue.
+  fn len(&self) -> usize;
+
+  /// Returns `true` iff the queue is empty.
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl<T: fmt::Debug + Ord + Codec + Clone, S: Read + Write + Seek> FalliblePriorityQueue for PagedWeakHeap<T, S> {
+  type Item = T;
+  type Error = io::Error;
+
+  fn push(&mut self, item: T) -> io::Result<()> {
+    PagedWeakHeap::push(self, item)
+  }
+
+  fn pop(&mut self) -> io::Result<Option<T>> {
+    PagedWeakHeap::pop(self)
+  }
+
+  fn peek(&self) -> Option<&T> {
+    PagedWeakHeap::peek(self)
+  }
+
+  fn len(&self) -> usize {
+    PagedWeakHeap::len(self)
+  }
+
+  fn is_empty(&self) -> bool {
+    PagedWeakHeap::is_empty(self)
+  }
+}
+
+/// A max-priority queue that is shared between threads, so that its methods
+/// take `&self`. Such a queue cannot lend out a reference to its greatest
+/// element, which is behind a lock, so there is no `peek`. Relaxed queues such as `MultiQueue` may pop an element near,
+/// rather than exactly, the greatest.
+pub trait SharedPriorityQueue {
+  /// The type of the elements in the queue.
+  type Item;
+
+  /// Pushes `item` onto the queue. Fails, returning `item`, if the queue has
+  /// been closed. A bounded queue blocks while it is full.
+  fn push(&self, item: Self::Item) -> Result<(), Self::Item>;
+
+  /// Removes the greatest element from the queue and returns it, or returns
+  /// `None` if the queue is empty, without waiting for an element to be pushed.
+  fn pop(&self) -> Option<Self::Item>;
+
+  /// Returns the number of elements in the queue. Other threads may change it
+  /// at any time.
+  fn len(&self) -> usize;
+
+  /// Returns `true` iff the queue is empty.
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl<T: fmt::Debug + Ord> SharedPriorityQueue for SyncWeakHeap<T> {
+  type Item = T;
+
+  fn push(&self, item: T) -> Result<(), T> {
+    SyncWeakHeap::push(self, item).map_err(|e| e.0)
+  }
+
+  fn pop(&self) -> Option<T> {
+    SyncWeakHeap::try_pop(self).ok()
+  }
+
+  fn len(&self) -> usize {
+    SyncWeakHeap::len(self)
+  }
+
+  fn is_empty(&self) -> bool {
+    SyncWeakHeap::is_empty(self)
+  }
+}
+
+impl<T: fmt::Debug + Ord> SharedPriorityQueue for AsyncWeakHeap<T> {
+  type Item = T;
+
+  fn push(&self, item: T) -> Result<(), T> {
+    AsyncWeakHeap::push(self, item).map_err(|e| e.0)
+  }
+
+  fn pop(&self) -> Option<T> {
+    AsyncWeakHeap::try_pop(self)
+  }
+
+  fn len(&self) -> usize {
+    AsyncWeakHeap::len(self)
+  }
+
+  fn is_empty(&self) -> bool {
+    AsyncWeakHeap::is_empty(self)
+  }
+}
+
+impl<T: fmt::Debug + Ord> SharedPriorityQueue for MultiQueue<T> {
+  type Item = T;
+
+  fn push(&self, item: T) -> Result<(), T> {
+    MultiQueue::push(self, item);
+    Ok(())
+  }
+
+  fn pop(&self) -> Option<T> {
+    MultiQueue::pop(self)
+  }
+
+  fn len(&self) -> usize {
+    MultiQueue::len(self)
+  }
+
+  fn is_empty(&self) -> bool {
+    MultiQueue::is_empty(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+  use super::{FalliblePriorityQueue, PriorityQueue, SharedPriorityQueue, StdBinaryHeap};
+  use crate::WeakHeap;
+  use crate::async_queue::AsyncWeakHeap;
+  use crate::multiqueue::MultiQueue;
+  use crate::paged::{PagedConfig, PagedWeakHeap};
+  use crate::sync::SyncWeakHeap;
+
+  fn check_ordering<Q: PriorityQueue<Item = i32> + Default>() {
+    let mut queue = Q::default();
+    assert!(queue.is_empty());
+    for x in &[5, 1, 9, 3, 9, 0] {
+      queue.push(*x);
+    }
+    assert_eq!(queue.len(), 6);
+    assert_eq!(queue.peek(), Some(&9));
+    let mut popped = Vec::new();
+    while let Some(x) = queue.pop() {
+      popped.push(x);
+    }
+    assert_eq!(popped, vec![9, 9, 5, 3, 1, 0]);
+    assert!(queue.is_empty());
+  }
+
+  #[test]
+  fn weak_heap() {
+    check_ordering::<WeakHeap<i32>>();
+  }
+
+  #[test]
+  fn std_binary_heap() {
+    check_ordering::<StdBinaryHeap<i32>>();
+  }
+
+  #[test]
+  fn paged_weak_heap() {
+    let config = PagedConfig { resident_levels: 3, page_records: 2, cache_pages: 2, };
+    let mut queue = PagedWeakHeap::with_storage(Cursor::new(Vec::new()), config);
+    assert!(FalliblePriorityQueue::is_empty(&queue));
+    for x in &[5, 1, 9, 3, 9, 0, 7, 2, 8] {
+      FalliblePriorityQueue::push(&mut queue, *x as u32).unwrap();
+    }
+    assert_eq!(FalliblePriorityQueue::len(&queue), 9);
+    assert_eq!(FalliblePriorityQueue::peek(&queue), Some(&9));
+    let mut popped = Vec::new();
+    while let Some(x) = FalliblePriorityQueue::pop(&mut queue).unwrap() {
+      popped.push(x);
+    }
+    assert_eq!(popped, vec![9, 9, 8, 7, 5, 3, 2, 1, 0]);
+  }
+
+  fn check_shared<Q: SharedPriorityQueue<Item = i32>>(queue: Q, exact: bool) {
+    assert!(queue.is_empty());
+    for x in &[5, 1, 9, 3, 9, 0] {
+      queue.push(*x).unwrap();
+    }
+    assert_eq!(queue.len(), 6);
+    let mut popped: Vec<i32> = std::iter::from_fn(|| queue.pop()).collect();
+    if !exact {
+      popped.sort_unstable_by(|a, b| b.cmp(a));
+    }
+    assert_eq!(popped, vec![9, 9, 5, 3, 1, 0]);
+    assert!(queue.is_empty());
+  }
+
+  #[test]
+  fn shared_queues() {
+    check_shared(SyncWeakHeap::new(), true);
+    check_shared(AsyncWeakHeap::new(), true);
+    check_shared(MultiQueue::new(2), false);
+    let closed = SyncWeakHeap::new();
+    closed.close();
+    assert_eq!(SharedPriorityQueue::push(&closed, 1), Err(1));
+  }
+}
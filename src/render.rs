@@ -0,0 +1,197 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Renders the structure of a `WeakHeap` as Graphviz DOT or ASCII art.
+//!
+//! A weak heap can be drawn in two ways (see `View`). In both, each element is
+//! labelled with its offset in the heap's storage and its value, and the links
+//! from elements to their distinguished ancestors are highlighted: these are
+//! the links along which the heap is ordered.
+//!
+//! ```rust
+//! # use weak_heap::WeakHeap;
+//! # use weak_heap::render::View;
+//! # fn main() {
+//! let heap: WeakHeap<i32> = vec![1, 2, 3].into_iter().collect();
+//! assert_eq!(heap.render_ascii(View::MultiWay), "\
+//! [0] 3
+//! |== [1] 2
+//! `== [2] 1
+//! ");
+//! # }
+//! ```
+
+use std::fmt::{self, Write};
+use crate::{Observer, WeakHeap};
+
+/// The shape in which to draw a heap.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum View {
+  /// The binary tree stored in the heap, with the left and right children of
+  /// each element swapped according to its valence bit. The root has only a
+  /// right child. Each right child's distinguished ancestor is its parent, so
+  /// right edges are highlighted. A left child shares the distinguished
+  /// ancestor of its parent, which is shown separately.
+  Binary,
+  /// The equivalent heap-ordered tree, in which the children of each element
+  /// are the elements of which it is the distinguished ancestor. Every edge in
+  /// this view is a distinguished-ancestor link.
+  MultiWay,
+}
+
+impl<T: fmt::Debug + Ord, O: Observer> WeakHeap<T, O> {
+  /// Renders the heap as a Graphviz DOT digraph.
+  pub fn render_dot(&self, view: View) -> String {
+    let mut out = String::new();
+    out.push_str("digraph weak_heap {\n  ordering=out;\n  node [shape=box];\n");
+    for (offset, entry) in self.data.iter().enumerate() {
+      let label = escape_dot(&format!("[{}] {:?}", offset, entry.value));
+      writeln!(out, "  n{} [label=\"{}\"];", offset, label).unwrap();
+    }
+    for offset in 0..self.len() {
+      match view {
+        View::Binary => {
+          if let Some(left) = self.left_child_offset(offset) {
+            writeln!(out, "  n{} -> n{} [label=\"L\"];", offset, left).unwrap();
+            writeln!(out, "  n{} -> n{} [style=dashed, color=red, constraint=false];",
+                     left, self.distinguished_ancestor_offset(left)).unwrap();
+          }
+          if let Some(right) = self.right_child_offset(offset) {
+            writeln!(out, "  n{} -> n{} [label=\"R\", color=red, penwidth=2];", offset, right).unwrap();
+          }
+        },
+        View::MultiWay => {
+          for child in self.children_offsets(offset) {
+            writeln!(out, "  n{} -> n{} [color=red, penwidth=2];", offset, child).unwrap();
+          }
+        },
+      }
+    }
+    out.push_str("}\n");
+    out
+  }
+
+  /// Renders the heap as an indented tree of ASCII text, one element per line.
+  /// Edges drawn with `==` are distinguished-ancestor links. In the binary
+  /// view, edges are marked `L` or `R`, and each left child is annotated with
+  /// the offset of its distinguished ancestor.
+  pub fn render_ascii(&self, view: View) -> String {
+    let mut out = String::new();
+    if !self.is_empty() {
+      self.render_ascii_node(&mut out, view, 0, "", None);
+    }
+    out
+  }
+
+  /// Renders the element at `offset` and its subtree. `prefix` is the
+  /// indentation of the element's line, and `edge` is the edge leading to it
+  /// (with whether it is the last edge from its parent), if it is not the
+  /// root.
+  fn render_ascii_node(&self, out: &mut String, view: View, offset: usize, prefix: &str,
+                       edge: Option<(&str, bool)>) {
+    let child_prefix = match edge {
+      Some((marker, last)) => {
+        write!(out, "{}{}{}", prefix, if last { '`' } else { '|' }, marker).unwrap();
+        format!("{}{}   ", prefix, if last { ' ' } else { '|' })
+      },
+      None => String::new(),
+    };
+    write!(out, "[{}] {:?}", offset, self.data[offset].value).unwrap();
+    let mut children = Vec::new();
+    match view {
+      View::Binary => {
+        if let Some(left) = self.left_child_offset(offset) {
+          children.push(("-L ", left));
+        }
+        if let Some(right) = self.right_child_offset(offset) {
+          children.push(("=R ", right));
+        }
+        if let Some(("-L ", _)) = edge {
+          write!(out, " (ancestor [{}])", self.distinguished_ancestor_offset(offset)).unwrap();
+        }
+      },
+      View::MultiWay => children.extend(self.children_offsets(offset).map(|child| ("== ", child))),
+    }
+    out.push('\n');
+    let count = children.len();
+    for (i, (marker, child)) in children.into_iter().enumerate() {
+      self.render_ascii_node(out, view, child, &child_prefix, Some((marker, i + 1 == count)));
+    }
+  }
+}
+
+/// Escapes `s` for use in a double-quoted DOT string.
+fn escape_dot(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::View;
+  use crate::WeakHeap;
+
+  fn heap() -> WeakHeap<i32> {
+    (0..6).collect()
+  }
+
+  #[test]
+  fn ascii_binary() {
+    assert_eq!(heap().render_ascii(View::Binary), "\
+[0] 5
+`=R [1] 3
+    |-L [2] 4 (ancestor [0])
+    |   |-L [5] 2 (ancestor [0])
+    |   `=R [4] 0
+    `=R [3] 1
+");
+  }
+
+  #[test]
+  fn ascii_multi_way() {
+    assert_eq!(heap().render_ascii(View::MultiWay), "\
+[0] 5
+|== [1] 3
+|   `== [3] 1
+|== [2] 4
+|   `== [4] 0
+`== [5] 2
+");
+  }
+
+  #[test]
+  fn dot() {
+    let dot = heap().render_dot(View::Binary);
+    assert!(dot.starts_with("digraph weak_heap {\n"));
+    assert!(dot.contains("  n2 [label=\"[2] 4\"];\n"));
+    assert!(dot.contains("  n0 -> n1 [label=\"R\", color=red, penwidth=2];\n"));
+    assert!(dot.contains("  n1 -> n2 [label=\"L\"];\n"));
+    assert!(dot.contains("  n2 -> n0 [style=dashed, color=red, constraint=false];\n"));
+    let dot = heap().render_dot(View::MultiWay);
+    assert!(dot.contains("  n0 -> n5 [color=red, penwidth=2];\n"));
+    assert!(!dot.contains("dashed"));
+  }
+
+  #[test]
+  fn dot_escapes_labels() {
+    let heap: WeakHeap<&str> = WeakHeap::from_vec(vec!["a\"b"]);
+    assert!(heap.render_dot(View::Binary).contains(r#"[label="[0] \"a\\\"b\""]"#));
+  }
+
+  #[test]
+  fn empty() {
+    let heap = WeakHeap::<i32>::new();
+    assert_eq!(heap.render_ascii(View::Binary), "");
+    assert_eq!(heap.render_dot(View::MultiWay), "digraph weak_heap {\n  ordering=out;\n  node [shape=box];\n}\n");
+  }
+}
@@ -0,0 +1,124 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Operation scripts for fuzzing `WeakHeap`.
+//!
+//! A `Script` can be generated from unstructured bytes with the `arbitrary`
+//! crate and replayed against both a `WeakHeap` and a naive sorted `Vec`. See
+//! `fuzz/fuzz_targets/script.rs` for a libFuzzer target that does this.
+
+use arbitrary::Arbitrary;
+use std::mem;
+use crate::{PeekMut, WeakHeap};
+
+/// A single operation in a `Script`.
+#[derive(Arbitrary, Clone, Debug)]
+pub enum Op {
+  /// Pushes a value onto the heap.
+  Push(i16),
+  /// Pops the top value from the heap.
+  Pop,
+  /// Peeks at the top value of the heap.
+  Peek,
+  /// Overwrites the top value of the heap through `WeakHeap::peek_mut`.
+  PeekMutReplace(i16),
+  /// Pops the top value of the heap through `PeekMut::pop`.
+  PeekMutPop,
+  /// Retains the values that are not multiples of `modulus`. A `modulus` of
+  /// zero retains every value.
+  Retain { modulus: u8 },
+  /// Appends a heap holding the given values.
+  Append(Vec<i16>),
+  /// Replaces the heap with an empty one, checking the output of
+  /// `WeakHeap::into_sorted_vec` on the old heap.
+  IntoSortedVec,
+}
+
+/// A sequence of operations to replay against a `WeakHeap`.
+#[derive(Arbitrary, Clone, Debug)]
+pub struct Script {
+  pub ops: Vec<Op>,
+}
+
+impl Script {
+  /// Replays this script against a `WeakHeap` and a sorted `Vec` acting as a
+  /// model of it. Panics if the two disagree, or if the heap invariants are
+  /// violated after any operation.
+  pub fn replay(&self) {
+    let mut heap = WeakHeap::new();
+    let mut model: Vec<i16> = Vec::new();
+    for op in &self.ops {
+      match op {
+        Op::Push(x) => {
+          heap.push(*x);
+          insert_sorted(&mut model, *x);
+        },
+        Op::Pop => assert_eq!(heap.pop(), model.pop()),
+        Op::Peek => assert_eq!(heap.peek(), model.last()),
+        Op::PeekMutReplace(x) => {
+          if let Some(mut top) = heap.peek_mut() {
+            *top = *x;
+            model.pop();
+            insert_sorted(&mut model, *x);
+          }
+        },
+        Op::PeekMutPop => assert_eq!(heap.peek_mut().map(PeekMut::pop), model.pop()),
+        Op::Retain { modulus } => {
+          let keep = |x: &i16| *modulus == 0 || x % i16::from(*modulus) != 0;
+          heap.retain(keep);
+          model.retain(keep);
+        },
+        Op::Append(values) => {
+          heap.append(&mut values.iter().cloned().collect());
+          for x in values {
+            insert_sorted(&mut model, *x);
+          }
+        },
+        Op::IntoSortedVec => {
+          assert_eq!(mem::take(&mut heap).into_sorted_vec(), mem::take(&mut model));
+        },
+      }
+      assert_eq!(heap.len(), model.len(), "after {:?}", op);
+      assert_eq!(heap.peek(), model.last(), "after {:?}", op);
+      if let Err(e) = heap.check_invariants() {
+        panic!("after {:?}: {}", op, e);
+      }
+    }
+    assert_eq!(heap.into_sorted_vec(), model);
+  }
+}
+
+/// Inserts `x` into `sorted`, keeping it in ascending order.
+fn insert_sorted(sorted: &mut Vec<i16>, x: i16) {
+  let i = sorted.binary_search(&x).unwrap_or_else(|i| i);
+  sorted.insert(i, x);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Script;
+  use arbitrary::{Arbitrary, Unstructured};
+  use rand::rngs::StdRng;
+  use rand::{Rng, SeedableRng};
+
+  #[test]
+  fn replay_random_scripts() {
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..500 {
+      let bytes: Vec<u8> = (0..4096).map(|_| rng.gen()).collect();
+      let script = Script::arbitrary(&mut Unstructured::new(&bytes)).unwrap();
+      script.replay();
+    }
+  }
+}
@@ -0,0 +1,154 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Selecting the greatest elements of a stream in bounded memory.
+
+use std::cmp::Reverse;
+use std::fmt;
+use std::mem;
+use crate::WeakHeap;
+
+/// The most elements `TopK::new` allocates room for up front. Beyond this the
+/// heap grows as elements are kept, so a huge `k` costs nothing until it is
+/// used.
+const MAX_INITIAL_CAPACITY: usize = 1024;
+
+/// Keeps the `k` greatest elements pushed into it, using O(k) memory.
+///
+/// The kept elements are held in a `WeakHeap` ordered so that the least of them
+/// is on top. Each push compares against that element first, so once the
+/// selector is full an element that is not among the greatest `k` costs a
+/// single comparison.
+///
+/// ```rust
+/// # use weak_heap::select::TopK;
+/// # fn main() {
+/// let mut top = TopK::new(3);
+/// top.extend(vec![5, 1, 9, 7, 3, 8]);
+/// assert_eq!(top.into_sorted_vec(), vec![9, 8, 7]);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TopK<T: fmt::Debug + Ord> {
+  heap: WeakHeap<Reverse<T>>,
+  k: usize,
+}
+
+impl<T: fmt::Debug + Ord> TopK<T> {
+  /// Creates a selector that keeps the `k` greatest elements.
+  pub fn new(k: usize) -> Self {
+    TopK { heap: WeakHeap::with_capacity(k.min(MAX_INITIAL_CAPACITY)), k, }
+  }
+
+  /// Returns the number of elements kept so far, at most `k`.
+  pub fn len(&self) -> usize {
+    self.heap.len()
+  }
+
+  /// Returns `true` iff no elements have been kept.
+  pub fn is_empty(&self) -> bool {
+    self.heap.is_empty()
+  }
+
+  /// Returns the least element kept, which a new element must exceed to be
+  /// kept once `k` elements are held.
+  pub fn threshold(&self) -> Option<&T> {
+    self.heap.peek().map(|x| &x.0)
+  }
+
+  /// Offers `value` to the selector. Returns the element that is no longer
+  /// kept as a result: either `value` itself, or the previous least kept
+  /// element that it displaced. Among equal elements, those offered first are
+  /// kept.
+  pub fn push(&mut self, value: T) -> Option<T> {
+    if self.heap.len() < self.k {
+      self.heap.push(Reverse(value));
+      return None;
+    }
+    match self.heap.peek_mut() {
+      Some(mut least) if value > least.0 => Some(mem::replace(&mut *least, Reverse(value)).0),
+      _ => Some(value),
+    }
+  }
+
+  /// Consumes the selector and returns the elements kept, greatest first.
+  pub fn into_sorted_vec(self) -> Vec<T> {
+    self.heap.into_sorted_vec().into_iter().map(|x| x.0).collect()
+  }
+}
+
+impl<T: fmt::Debug + Ord> Extend<T> for TopK<T> {
+  fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+    for value in iter {
+      self.push(value);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use rand::{Rng, SeedableRng};
+  use rand::rngs::StdRng;
+  use super::TopK;
+  use crate::counted::{ComparisonCounts, Counted};
+
+  #[test]
+  fn matches_sort() {
+    let mut rng = StdRng::seed_from_u64(45);
+    for k in &[0, 1, 2, 7, 100, 2000] {
+      let values: Vec<i32> = (0..1000).map(|_| rng.gen_range(0, 500)).collect();
+      let mut top = TopK::new(*k);
+      let mut rejected = 0;
+      for x in &values {
+        rejected += top.push(*x).is_some() as usize;
+      }
+      let mut expected = values.clone();
+      expected.sort_by(|a, b| b.cmp(a));
+      expected.truncate(*k);
+      assert_eq!(top.len(), expected.len());
+      assert_eq!(rejected, values.len() - expected.len());
+      assert_eq!(top.into_sorted_vec(), expected);
+    }
+  }
+
+  #[test]
+  fn huge_k_allocates_lazily() {
+    let mut top = TopK::new(usize::MAX);
+    top.extend(vec![3, 1, 2]);
+    assert_eq!(top.into_sorted_vec(), vec![3, 2, 1]);
+  }
+
+  #[test]
+  fn keeps_earliest_of_equals() {
+    let mut top = TopK::new(2);
+    for (key, seq) in &[(1, 'a'), (1, 'b'), (1, 'c')] {
+      top.push((*key, std::cmp::Reverse(*seq)));
+    }
+    let kept: Vec<char> = top.into_sorted_vec().into_iter().map(|(_, seq)| seq.0).collect();
+    assert_eq!(kept, vec!['a', 'b']);
+  }
+
+  #[test]
+  fn rejects_with_one_comparison() {
+    let mut top = TopK::new(10);
+    top.extend((0..10).map(|x| Counted(x + 100)));
+    let (_, counts) = ComparisonCounts::scope(|| {
+      for x in 0..1000 {
+        top.push(Counted(x % 100));
+      }
+    });
+    assert_eq!(counts.total(), 1000);
+    assert_eq!(top.threshold(), Some(&Counted(100)));
+  }
+}
@@ -0,0 +1,234 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `Serialize` and `Deserialize` implementations for `WeakHeap`, enabled by
+//! the `serde` feature.
+//!
+//! A heap is serialized as a struct with two fields: `elements`, the elements
+//! in storage order, and `valence`, the valence bits packed eight to a byte,
+//! least significant bit first. Deserializing restores exactly this layout, so
+//! it takes O(n) time and makes no comparisons. The structure is checked, that
+//! is, the valence bitmap must have one bit per element and no bits set beyond
+//! them, but the heap ordering is not; call `WeakHeap::check_invariants` on the
+//! result if the input is untrusted.
+
+use std::fmt;
+use std::marker::PhantomData;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeSeq, SerializeStruct, Serializer};
+use crate::{HeapEntry, Observer, WeakHeap};
+use crate::counted::Counted;
+use crate::snapshot::{pack_valence, restore};
+
+const FIELDS: &[&str] = &["elements", "valence"];
+
+/// Serializes the elements of a heap as a sequence, without copying them.
+struct Elements<'a, T: fmt::Debug>(&'a [HeapEntry<T>]);
+
+impl<'a, T: fmt::Debug + Serialize> Serialize for Elements<'a, T> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+    for entry in self.0 {
+      seq.serialize_element(&entry.value)?;
+    }
+    seq.end()
+  }
+}
+
+impl<T: fmt::Debug + Ord + Serialize, O: Observer> Serialize for WeakHeap<T, O> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut state = serializer.serialize_struct("WeakHeap", 2)?;
+    state.serialize_field("elements", &Elements(&self.data))?;
+    state.serialize_field("valence", &ValenceBytes(&pack_valence(&self.data)))?;
+    state.end()
+  }
+}
+
+/// Serializes packed valence bits as a byte string.
+struct ValenceBytes<'a>(&'a [u8]);
+
+impl<'a> Serialize for ValenceBytes<'a> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(self.0)
+  }
+}
+
+/// Deserializes packed valence bits from either a byte string or a sequence
+/// of bytes, as self-describing formats like JSON produce the latter.
+struct ValenceBuf(Vec<u8>);
+
+impl<'de> Deserialize<'de> for ValenceBuf {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+      type Value = ValenceBuf;
+
+      fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a valence bitmap")
+      }
+
+      fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<ValenceBuf, E> {
+        Ok(ValenceBuf(v.to_vec()))
+      }
+
+      fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<ValenceBuf, E> {
+        Ok(ValenceBuf(v))
+      }
+
+      fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ValenceBuf, A::Error> {
+        // The hint comes from the input, so do not trust it with a large
+        // allocation.
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(byte) = seq.next_element()? {
+          bytes.push(byte);
+        }
+        Ok(ValenceBuf(bytes))
+      }
+    }
+
+    deserializer.deserialize_bytes(BytesVisitor)
+  }
+}
+
+struct HeapVisitor<T, O> {
+  marker: PhantomData<fn() -> (T, O)>,
+}
+
+impl<T, O> HeapVisitor<T, O>
+where T: fmt::Debug + Ord, O: Observer + Default {
+  fn build<E: de::Error>(elements: Vec<T>, valence: ValenceBuf) -> Result<WeakHeap<T, O>, E> {
+    restore(elements, &valence.0).map_err(E::custom)
+  }
+}
+
+impl<'de, T, O> Visitor<'de> for HeapVisitor<T, O>
+where T: fmt::Debug + Ord + Deserialize<'de>, O: Observer + Default {
+  type Value = WeakHeap<T, O>;
+
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("struct WeakHeap")
+  }
+
+  fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+    let elements = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+    let valence = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+    Self::build(elements, valence)
+  }
+
+  fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+    let mut elements = None;
+    let mut valence = None;
+    while let Some(key) = map.next_key::<String>()? {
+      match key.as_str() {
+        "elements" => {
+          if elements.is_some() {
+            return Err(de::Error::duplicate_field("elements"));
+          }
+          elements = Some(map.next_value()?);
+        },
+        "valence" => {
+          if valence.is_some() {
+            return Err(de::Error::duplicate_field("valence"));
+          }
+          valence = Some(map.next_value()?);
+        },
+        other => return Err(de::Error::unknown_field(other, FIELDS)),
+      }
+    }
+    let elements = elements.ok_or_else(|| de::Error::missing_field("elements"))?;
+    let valence = valence.ok_or_else(|| de::Error::missing_field("valence"))?;
+    Self::build(elements, valence)
+  }
+}
+
+impl<'de, T, O> Deserialize<'de> for WeakHeap<T, O>
+where T: fmt::Debug + Ord + Deserialize<'de>, O: Observer + Default {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    deserializer.deserialize_struct("WeakHeap", FIELDS, HeapVisitor { marker: PhantomData, })
+  }
+}
+
+/// `Counted` serializes transparently as the value it wraps.
+impl<T: Serialize> Serialize for Counted<T> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    self.0.serialize(serializer)
+  }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Counted<T> {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    T::deserialize(deserializer).map(Counted)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::WeakHeap;
+
+  fn layout<T: std::fmt::Debug + Ord + Clone>(heap: &WeakHeap<T>) -> Vec<(T, bool)> {
+    heap.tree().nodes().map(|n| (n.value().clone(), n.valence())).collect()
+  }
+
+  #[test]
+  fn round_trip_preserves_layout() {
+    let mut heap: WeakHeap<i32> = (0..37).map(|x| (x * 11) % 37).collect();
+    for x in 0..10 {
+      heap.pop();
+      heap.push(x * 5);
+    }
+    let json = serde_json::to_string(&heap).unwrap();
+    let loaded: WeakHeap<i32> = serde_json::from_str(&json).unwrap();
+    assert_eq!(layout(&loaded), layout(&heap));
+    assert!(loaded.check_invariants().is_ok());
+    assert_eq!(loaded.into_sorted_vec(), heap.into_sorted_vec());
+  }
+
+  #[test]
+  fn load_makes_no_comparisons() {
+    use crate::counted::{ComparisonCounts, Counted};
+    let heap: WeakHeap<Counted<u32>> = (0..100).map(Counted).collect();
+    let json = serde_json::to_string(&heap).unwrap();
+    let (loaded, counts) = ComparisonCounts::scope(|| {
+      serde_json::from_str::<WeakHeap<Counted<u32>>>(&json).unwrap()
+    });
+    assert_eq!(counts.total(), 0);
+    assert_eq!(loaded.len(), 100);
+  }
+
+  #[test]
+  fn empty() {
+    let json = serde_json::to_string(&WeakHeap::<i32>::new()).unwrap();
+    assert_eq!(json, r#"{"elements":[],"valence":[]}"#);
+    assert!(serde_json::from_str::<WeakHeap<i32>>(&json).unwrap().is_empty());
+  }
+
+  #[test]
+  fn rejects_malformed() {
+    let error = |json: &str| serde_json::from_str::<WeakHeap<i32>>(json).unwrap_err().to_string();
+    assert!(error(r#"{"elements":[3,2,1],"valence":[]}"#)
+            .contains("valence bitmap has 0 bytes, but 3 elements require 1"));
+    assert!(error(r#"{"elements":[3,2,1],"valence":[8]}"#)
+            .contains("bits set beyond the last of 3 elements"));
+    assert!(error(r#"{"elements":[3,2,1]}"#).contains("missing field `valence`"));
+    assert!(error(r#"{"elements":[3],"valence":[0],"extra":1}"#).contains("unknown field `extra`"));
+  }
+
+  #[test]
+  fn ordering_is_checked_on_request() {
+    let heap: WeakHeap<i32> = serde_json::from_str(r#"{"elements":[1,2,3],"valence":[0]}"#).unwrap();
+    assert_eq!(heap.check_invariants().unwrap_err().to_string(),
+               "element 2 at offset 1 is greater than its distinguished ancestor 1 at offset 0");
+  }
+}
@@ -0,0 +1,431 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A compact binary snapshot format for `WeakHeap`s whose elements implement
+//! `Codec`.
+//!
+//! A snapshot records the heap's layout exactly, so it can be reloaded in O(n)
+//! time without any comparisons. All integers are little-endian. A snapshot
+//! consists of:
+//!
+//! * the magic bytes `WEAKHEAP`;
+//! * the format version, a `u32`, currently 1;
+//! * the number of elements, a `u64`;
+//! * the size in bytes of each encoded element, a `u32`;
+//! * the valence bits in storage order, packed eight to a byte, least
+//!   significant bit first, with any unused bits of the last byte clear;
+//! * each element in storage order, as encoded by `Codec::encode`;
+//! * the CRC-32 (IEEE) of everything above, a `u32`.
+//!
+//! ```rust
+//! # use weak_heap::WeakHeap;
+//! # fn main() {
+//! let heap: WeakHeap<u32> = (0..100).collect();
+//! let mut bytes = Vec::new();
+//! heap.write_snapshot(&mut bytes).unwrap();
+//! let loaded = WeakHeap::<u32>::read_snapshot(&mut &bytes[..]).unwrap();
+//! assert_eq!(loaded.into_sorted_vec(), heap.into_sorted_vec());
+//! # }
+//! ```
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use crate::codec::Codec;
+use crate::{HeapEntry, Observer, WeakHeap};
+
+const MAGIC: &[u8; 8] = b"WEAKHEAP";
+
+/// The snapshot format version written by `write_snapshot`.
+pub const VERSION: u32 = 1;
+
+/// The number of elements encoded or decoded at a time.
+const CHUNK: usize = 4096;
+
+/// An error encountered while reading a snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+  /// The underlying reader failed, or the snapshot ended early.
+  Io(io::Error),
+  /// The input does not begin with the snapshot magic bytes.
+  BadMagic,
+  /// The snapshot was written in a format version this crate cannot read.
+  UnsupportedVersion(u32),
+  /// The snapshot's elements were encoded with a different size than the
+  /// element type's `Codec::SIZE`.
+  ElementSize { expected: usize, found: u64, },
+  /// The valence bitmap is inconsistent with the number of elements.
+  Malformed(String),
+  /// The element at `offset` could not be decoded.
+  Decode { offset: usize, error: io::Error, },
+  /// The checksum stored in the snapshot does not match its contents.
+  Checksum { expected: u32, found: u32, },
+}
+
+impl fmt::Display for SnapshotError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SnapshotError::Io(e) => write!(f, "snapshot I/O error: {}", e),
+      SnapshotError::BadMagic => write!(f, "input is not a weak heap snapshot"),
+      SnapshotError::UnsupportedVersion(v) =>
+        write!(f, "snapshot version {} is not supported (expected {})", v, VERSION),
+      SnapshotError::ElementSize { expected, found, } =>
+        write!(f, "snapshot elements are {} bytes, but the element type encodes to {}",
+               found, expected),
+      SnapshotError::Malformed(reason) => write!(f, "malformed snapshot: {}", reason),
+      SnapshotError::Decode { offset, error, } =>
+        write!(f, "cannot decode snapshot element at offset {}: {}", offset, error),
+      SnapshotError::Checksum { expected, found, } =>
+        write!(f, "snapshot checksum is {:08x}, but its contents have checksum {:08x}",
+               expected, found),
+    }
+  }
+}
+
+impl Error for SnapshotError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      SnapshotError::Io(e) | SnapshotError::Decode { error: e, .. } => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for SnapshotError {
+  fn from(e: io::Error) -> Self {
+    SnapshotError::Io(e)
+  }
+}
+
+/// Returns the valence bits of `entries`, packed eight to a byte.
+pub(crate) fn pack_valence<T: fmt::Debug>(entries: &[HeapEntry<T>]) -> Vec<u8> {
+  let mut bits = vec![0u8; entries.len().div_ceil(8)];
+  for (offset, entry) in entries.iter().enumerate() {
+    bits[offset / 8] |= (entry.valence as u8) << (offset % 8);
+  }
+  bits
+}
+
+/// Restores a heap, observed by `O::default()`, from its elements in storage
+/// order and their packed valence bits. Only the structure is checked, that the
+/// bits fit the elements; the heap ordering is left to `check_invariants`.
+/// Shared by `read_snapshot` and the `serde` implementation.
+pub(crate) fn restore<T, O>(elements: Vec<T>, bits: &[u8]) -> Result<WeakHeap<T, O>, String>
+where T: fmt::Debug + Ord, O: Observer + Default {
+  let mut heap = WeakHeap::with_observer(O::default());
+  heap.data = unpack_valence(elements, bits)?;
+  heap.hooks.rebuilt(heap.len());
+  Ok(heap)
+}
+
+/// Pairs `elements` with their packed valence bits, or describes why the bits
+/// do not fit them.
+fn unpack_valence<T: fmt::Debug>(elements: Vec<T>, bits: &[u8])
+                                            -> Result<Vec<HeapEntry<T>>, String> {
+  let expected = elements.len().div_ceil(8);
+  if bits.len() != expected {
+    return Err(format!("valence bitmap has {} bytes, but {} elements require {}",
+                       bits.len(), elements.len(), expected));
+  }
+  if let Some(last) = bits.last() {
+    let used = elements.len() - 8 * (expected - 1);
+    if used < 8 && last >> used != 0 {
+      return Err(format!("valence bitmap has bits set beyond the last of {} elements",
+                         elements.len()));
+    }
+  }
+  Ok(elements.into_iter().enumerate()
+     .map(|(offset, value)| HeapEntry { valence: bits[offset / 8] >> (offset % 8) & 1 == 1, value, })
+     .collect())
+}
+
+const CRC_TABLE: [u32; 256] = {
+  let mut table = [0u32; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut crc = i as u32;
+    let mut bit = 0;
+    while bit < 8 {
+      crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+      bit += 1;
+    }
+    table[i] = crc;
+    i += 1;
+  }
+  table
+};
+
+/// A running CRC-32 (IEEE) checksum.
+struct Crc32(u32);
+
+impl Crc32 {
+  fn new() -> Self {
+    Crc32(!0)
+  }
+
+  fn update(&mut self, bytes: &[u8]) {
+    for b in bytes {
+      self.0 = CRC_TABLE[((self.0 ^ *b as u32) & 0xff) as usize] ^ (self.0 >> 8);
+    }
+  }
+
+  fn finish(&self) -> u32 {
+    !self.0
+  }
+}
+
+/// Wraps a writer, checksumming everything written through it.
+struct ChecksumWriter<'a, W: Write> {
+  inner: &'a mut W,
+  crc: Crc32,
+}
+
+impl<'a, W: Write> ChecksumWriter<'a, W> {
+  fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+    self.crc.update(bytes);
+    self.inner.write_all(bytes)
+  }
+}
+
+/// Wraps a reader, checksumming everything read through it.
+struct ChecksumReader<'a, R: Read> {
+  inner: &'a mut R,
+  crc: Crc32,
+}
+
+impl<'a, R: Read> ChecksumReader<'a, R> {
+  fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+    self.inner.read_exact(buf)?;
+    self.crc.update(buf);
+    Ok(())
+  }
+
+  /// Reads exactly `len` bytes without trusting `len` for the allocation, so
+  /// that a corrupt length fails at the end of input instead of exhausting
+  /// memory.
+  fn read_vec(&mut self, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    self.inner.by_ref().take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    self.crc.update(&buf);
+    Ok(buf)
+  }
+}
+
+impl<T: fmt::Debug + Ord + Codec, O: Observer> WeakHeap<T, O> {
+  /// Writes a snapshot of the heap to `writer`. See the `snapshot` module for
+  /// the format.
+  pub fn write_snapshot<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    let mut out = ChecksumWriter { inner: writer, crc: Crc32::new(), };
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&(self.len() as u64).to_le_bytes())?;
+    out.write_all(&(T::SIZE as u32).to_le_bytes())?;
+    out.write_all(&pack_valence(&self.data))?;
+    let mut buf = vec![0; T::SIZE * CHUNK.min(self.len())];
+    for chunk in self.data.chunks(CHUNK) {
+      let buf = &mut buf[..T::SIZE * chunk.len()];
+      for (entry, record) in chunk.iter().zip(buf.chunks_exact_mut(T::SIZE.max(1))) {
+        entry.value.encode(record);
+      }
+      out.write_all(buf)?;
+    }
+    let checksum = out.crc.finish();
+    out.inner.write_all(&checksum.to_le_bytes())
+  }
+}
+
+impl<T: fmt::Debug + Ord + Codec, O: Observer + Default> WeakHeap<T, O> {
+  /// Reads a heap from a snapshot written by `write_snapshot`, observed by
+  /// `O::default()`. This takes O(n) time and makes no comparisons. The
+  /// snapshot's layout and checksum are verified, but its heap ordering is not;
+  /// call `check_invariants` on the result if that matters.
+  pub fn read_snapshot<R: Read>(reader: &mut R) -> Result<Self, SnapshotError> {
+    let mut input = ChecksumReader { inner: reader, crc: Crc32::new(), };
+    let mut header = [0; 24];
+    input.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+      return Err(SnapshotError::BadMagic);
+    }
+    let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if version != VERSION {
+      return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let mut count = [0; 8];
+    count.copy_from_slice(&header[12..20]);
+    let count = u64::from_le_bytes(count);
+    let size = u32::from_le_bytes([header[20], header[21], header[22], header[23]]) as u64;
+    if size != T::SIZE as u64 {
+      return Err(SnapshotError::ElementSize { expected: T::SIZE, found: size, });
+    }
+    let bits = input.read_vec(count.div_ceil(8))?;
+    let count = usize::try_from(count)
+      .map_err(|_| SnapshotError::Malformed(format!("{} elements do not fit in memory", count)))?;
+    let mut elements = Vec::new();
+    while elements.len() < count {
+      let n = CHUNK.min(count - elements.len());
+      let buf = input.read_vec((T::SIZE * n) as u64)?;
+      elements.reserve(n);
+      for i in 0..n {
+        let record = &buf[T::SIZE * i..T::SIZE * (i + 1)];
+        let offset = elements.len();
+        elements.push(T::decode(record).map_err(|error| SnapshotError::Decode { offset, error, })?);
+      }
+    }
+    let checksum = input.crc.finish();
+    let mut stored = [0; 4];
+    input.inner.read_exact(&mut stored)?;
+    let stored = u32::from_le_bytes(stored);
+    if stored != checksum {
+      return Err(SnapshotError::Checksum { expected: stored, found: checksum, });
+    }
+    restore(elements, &bits).map_err(SnapshotError::Malformed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io;
+  use super::{Crc32, SnapshotError};
+  use crate::WeakHeap;
+  use crate::codec::Codec;
+  use crate::observer::Stats;
+
+  fn snapshot<T: std::fmt::Debug + Ord + Codec>(heap: &WeakHeap<T>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    heap.write_snapshot(&mut bytes).unwrap();
+    bytes
+  }
+
+  fn layout<T: std::fmt::Debug + Ord + Clone>(heap: &WeakHeap<T>) -> Vec<(T, bool)> {
+    heap.tree().nodes().map(|n| (n.value().clone(), n.valence())).collect()
+  }
+
+  #[test]
+  fn crc32_check_value() {
+    let mut crc = Crc32::new();
+    crc.update(b"123456789");
+    assert_eq!(crc.finish(), 0xcbf4_3926);
+  }
+
+  #[test]
+  fn round_trip_preserves_layout() {
+    let mut heap: WeakHeap<i64> = (0..10_000).map(|x| (x * 7919) % 10_007).collect();
+    for x in 0..100 {
+      heap.pop();
+      heap.push(-x);
+    }
+    let bytes = snapshot(&heap);
+    assert_eq!(bytes.len(), 24 + 10_000 / 8 + 8 * 10_000 + 4);
+    let loaded = WeakHeap::<i64>::read_snapshot(&mut &bytes[..]).unwrap();
+    assert_eq!(layout(&loaded), layout(&heap));
+  }
+
+  #[test]
+  fn ordering_is_checked_on_request() {
+    let mut heap: WeakHeap<u32> = (0..20).collect();
+    heap.data[0].value = 0;
+    let loaded = WeakHeap::<u32>::read_snapshot(&mut &snapshot(&heap)[..]).unwrap();
+    assert!(loaded.check_invariants().is_err());
+  }
+
+  #[test]
+  fn reads_into_observed_heap() {
+    let heap: WeakHeap<u32> = (0..20).collect();
+    let bytes = snapshot(&heap);
+    let mut loaded = WeakHeap::<u32, Stats>::read_snapshot(&mut &bytes[..]).unwrap();
+    assert_eq!(loaded.len(), 20);
+    assert_eq!(loaded.pop(), Some(19));
+    assert!(loaded.observer().comparisons > 0);
+  }
+
+  #[test]
+  fn empty() {
+    let bytes = snapshot(&WeakHeap::<u8>::new());
+    assert_eq!(bytes.len(), 28);
+    assert!(WeakHeap::<u8>::read_snapshot(&mut &bytes[..]).unwrap().is_empty());
+  }
+
+  #[test]
+  fn rejects_corruption() {
+    let heap: WeakHeap<u32> = (0..20).collect();
+    let bytes = snapshot(&heap);
+    let read = |bytes: &[u8]| WeakHeap::<u32>::read_snapshot(&mut &bytes[..]).unwrap_err();
+
+    assert!(matches!(read(b"not a snapshot at all, sorry"), SnapshotError::BadMagic));
+    let mut wrong_version = bytes.clone();
+    wrong_version[8] = 2;
+    assert!(matches!(read(&wrong_version), SnapshotError::UnsupportedVersion(2)));
+    assert!(matches!(WeakHeap::<u64>::read_snapshot(&mut &bytes[..]).unwrap_err(),
+                     SnapshotError::ElementSize { expected: 8, found: 4, }));
+    let mut flipped = bytes.clone();
+    flipped[40] ^= 1;
+    assert!(matches!(read(&flipped), SnapshotError::Checksum { .. }));
+    match read(&bytes[..bytes.len() - 1]) {
+      SnapshotError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+      e => panic!("unexpected error {}", e),
+    }
+    // A huge element count runs out of input rather than memory.
+    let mut huge = bytes.clone();
+    huge[12..20].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+    assert!(matches!(read(&huge), SnapshotError::Io(_)));
+  }
+
+  #[test]
+  fn rejects_stray_valence_bits() {
+    let heap: WeakHeap<u32> = (0..3).collect();
+    let mut bytes = snapshot(&heap);
+    bytes[24] |= 0x80;
+    let len = bytes.len();
+    let mut crc = Crc32::new();
+    crc.update(&bytes[..len - 4]);
+    bytes[len - 4..].copy_from_slice(&crc.finish().to_le_bytes());
+    let e = WeakHeap::<u32>::read_snapshot(&mut &bytes[..]).unwrap_err();
+    assert!(matches!(e, SnapshotError::Malformed(_)));
+    assert_eq!(e.to_string(), "malformed snapshot: valence bitmap has bits set beyond the last of 3 elements");
+  }
+
+  #[test]
+  fn decode_errors_report_offset() {
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct Even(u8);
+
+    impl Codec for Even {
+      const SIZE: usize = 1;
+
+      fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.0;
+      }
+
+      fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf[0] & 1 == 0 {
+          Ok(Even(buf[0]))
+        } else {
+          Err(io::Error::new(io::ErrorKind::InvalidData, "odd value"))
+        }
+      }
+    }
+
+    let heap: WeakHeap<u8> = WeakHeap::from_vec(vec![4, 2, 3, 0]);
+    let bytes = snapshot(&heap);
+    let odd = heap.tree().nodes().position(|n| *n.value() == 3).unwrap();
+    match WeakHeap::<Even>::read_snapshot(&mut &bytes[..]).unwrap_err() {
+      SnapshotError::Decode { offset, .. } => assert_eq!(offset, odd),
+      e => panic!("unexpected error {}", e),
+    }
+  }
+}
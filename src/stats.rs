@@ -0,0 +1,99 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-heap operation counters, enabled by the `stats` feature.
+//!
+//! ```rust
+//! # use weak_heap::WeakHeap;
+//! # fn main() {
+//! let mut heap: WeakHeap<i32> = WeakHeap::new();
+//! heap.extend(vec![3, 1, 4, 1, 5]);
+//! heap.pop();
+//! let stats = heap.stats();
+//! assert_eq!(stats.pushes, 5);
+//! assert_eq!(stats.pops, 1);
+//! assert_eq!(stats.max_len, 5);
+//! # }
+//! ```
+
+use std::fmt;
+use crate::{Observer, WeakHeap};
+
+/// Counts of the work done by a single `WeakHeap`, as returned by
+/// `WeakHeap::stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HeapStats {
+  /// The number of elements pushed.
+  pub pushes: u64,
+  /// The number of elements popped.
+  pub pops: u64,
+  /// The number of comparisons made between elements.
+  pub comparisons: u64,
+  /// The number of times an element was written to a new position in the
+  /// heap's storage. A swap counts as two moves, and an element sifted up
+  /// counts one move per level it passes plus one for its final placement.
+  pub moves: u64,
+  /// The number of valence bits changed.
+  pub flips: u64,
+  /// The largest number of elements the heap has held.
+  pub max_len: usize,
+}
+
+impl<T: fmt::Debug + Ord, O: Observer> WeakHeap<T, O> {
+  /// Returns the counts of the work this heap has done since it was created or
+  /// `reset_stats` was last called.
+  pub fn stats(&self) -> HeapStats {
+    self.hooks.stats
+  }
+
+  /// Resets all counts to zero, except `max_len`, which is reset to the
+  /// current length of the heap.
+  pub fn reset_stats(&mut self) {
+    self.hooks.stats = HeapStats { max_len: self.len(), ..HeapStats::default() };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::WeakHeap;
+
+  #[test]
+  fn counts() {
+    let mut heap: WeakHeap<i32> = WeakHeap::new();
+    heap.extend(0..16);
+    let stats = heap.stats();
+    assert_eq!(stats.pushes, 16);
+    assert_eq!(stats.max_len, 16);
+    // Each ascending push climbs to the root, moving the element it passes at
+    // every level it compares against, and then writes itself into the root.
+    // Only the first push, into an empty heap, has nothing to climb past.
+    assert_eq!(stats.moves, 16 + stats.comparisons + 15);
+    while heap.pop().is_some() {}
+    assert_eq!(heap.stats().pops, 16);
+    assert_eq!(heap.stats().max_len, 16);
+  }
+
+  #[test]
+  fn reset() {
+    let mut heap = WeakHeap::from_vec((0..10).collect());
+    assert_eq!(heap.stats().comparisons, 9);
+    assert_eq!(heap.stats().max_len, 10);
+    heap.pop();
+    heap.reset_stats();
+    let stats = heap.stats();
+    assert_eq!(stats.pops, 0);
+    assert_eq!(stats.comparisons, 0);
+    assert_eq!(stats.max_len, 9);
+  }
+}
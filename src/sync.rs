@@ -0,0 +1,624 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A `WeakHeap` that can be shared between threads.
+//!
+//! ```rust
+//! # use std::sync::Arc;
+//! # use std::thread;
+//! # use weak_heap::sync::SyncWeakHeap;
+//! # fn main() {
+//! let queue = Arc::new(SyncWeakHeap::bounded(4));
+//! let producer = {
+//!   let queue = Arc::clone(&queue);
+//!   thread::spawn(move || {
+//!     for x in 0..100 {
+//!       queue.push(x).unwrap();
+//!     }
+//!     queue.close();
+//!   })
+//! };
+//! let mut total = 0;
+//! while let Some(x) = queue.pop() {
+//!   total += x;
+//! }
+//! producer.join().unwrap();
+//! assert_eq!(total, 4950);
+//! # }
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use crate::WeakHeap;
+
+/// An error returned by `SyncWeakHeap::push` when the queue is closed. It
+/// holds the value that could not be pushed.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct PushError<T>(pub T);
+
+impl<T> fmt::Debug for PushError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("PushError(..)")
+  }
+}
+
+impl<T> fmt::Display for PushError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("pushing onto a closed queue")
+  }
+}
+
+impl<T> Error for PushError<T> {}
+
+/// An error returned by `SyncWeakHeap::try_push`. It holds the value that could
+/// not be pushed.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum TryPushError<T> {
+  /// The queue is at capacity.
+  Full(T),
+  /// The queue is closed.
+  Closed(T),
+}
+
+impl<T> TryPushError<T> {
+  /// Returns the value that could not be pushed.
+  pub fn into_inner(self) -> T {
+    match self {
+      TryPushError::Full(x) | TryPushError::Closed(x) => x,
+    }
+  }
+}
+
+impl<T> fmt::Debug for TryPushError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TryPushError::Full(_) => f.write_str("Full(..)"),
+      TryPushError::Closed(_) => f.write_str("Closed(..)"),
+    }
+  }
+}
+
+impl<T> fmt::Display for TryPushError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TryPushError::Full(_) => f.write_str("pushing onto a full queue"),
+      TryPushError::Closed(_) => f.write_str("pushing onto a closed queue"),
+    }
+  }
+}
+
+impl<T> Error for TryPushError<T> {}
+
+/// An error returned by `SyncWeakHeap::try_pop`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TryPopError {
+  /// The queue is empty, but may have elements pushed later.
+  Empty,
+  /// The queue is empty and closed, so it will never have another element.
+  Closed,
+}
+
+impl fmt::Display for TryPopError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TryPopError::Empty => f.write_str("popping from an empty queue"),
+      TryPopError::Closed => f.write_str("popping from an empty and closed queue"),
+    }
+  }
+}
+
+impl Error for TryPopError {}
+
+/// An error returned by `SyncWeakHeap::pop_timeout`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PopTimeoutError {
+  /// No element was pushed before the timeout elapsed.
+  Timeout,
+  /// The queue is empty and closed, so it will never have another element.
+  Closed,
+}
+
+impl fmt::Display for PopTimeoutError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      PopTimeoutError::Timeout => f.write_str("timed out popping from an empty queue"),
+      PopTimeoutError::Closed => f.write_str("popping from an empty and closed queue"),
+    }
+  }
+}
+
+impl Error for PopTimeoutError {}
+
+#[derive(Debug)]
+struct State<T: fmt::Debug + Ord> {
+  heap: WeakHeap<T>,
+  closed: bool,
+}
+
+/// A max-heap that can be shared between threads, with blocking operations.
+///
+/// A queue may optionally have a capacity, in which case `push` blocks while it
+/// is full. Once a queue is closed, pushes fail, and pops return the remaining
+/// elements and then fail rather than block.
+///
+/// A panic from an `Ord` implementation while the heap is being modified may
+/// leave it out of order, so it poisons the queue: as the panic unwinds, the
+/// queue's elements are dropped, it is closed and every blocked thread is
+/// woken. Pushes then fail and pops report the queue closed, and `is_poisoned`
+/// tells this apart from `close`.
+#[derive(Debug)]
+pub struct SyncWeakHeap<T: fmt::Debug + Ord> {
+  state: Mutex<State<T>>,
+  /// Signalled when an element is pushed or the queue is closed.
+  not_empty: Condvar,
+  /// Signalled when an element is popped from a bounded queue or the queue is
+  /// closed.
+  not_full: Condvar,
+  capacity: Option<usize>,
+}
+
+impl<T: fmt::Debug + Ord> Default for SyncWeakHeap<T> {
+  fn default() -> Self {
+    SyncWeakHeap::new()
+  }
+}
+
+impl<T: fmt::Debug + Ord> SyncWeakHeap<T> {
+  /// Creates a new, unbounded queue.
+  pub fn new() -> Self {
+    SyncWeakHeap::with_capacity_bound(None)
+  }
+
+  /// Creates a new queue that holds at most `capacity` elements.
+  ///
+  /// Panics if `capacity` is zero.
+  pub fn bounded(capacity: usize) -> Self {
+    assert!(capacity > 0, "capacity must be positive");
+    SyncWeakHeap::with_capacity_bound(Some(capacity))
+  }
+
+  fn with_capacity_bound(capacity: Option<usize>) -> Self {
+    SyncWeakHeap {
+      state: Mutex::new(State { heap: WeakHeap::new(), closed: false, }),
+      not_empty: Condvar::new(),
+      not_full: Condvar::new(),
+      capacity,
+    }
+  }
+
+  /// Locks the queue's state. A panic while it was locked can only come from
+  /// `with_heap`, which has already closed and emptied the queue, so the state
+  /// is consistent even if the lock is poisoned.
+  fn lock(&self) -> MutexGuard<'_, State<T>> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn wait<'a>(&self, condvar: &Condvar, state: MutexGuard<'a, State<T>>) -> MutexGuard<'a, State<T>> {
+    condvar.wait(state).unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn wait_timeout<'a>(&self, condvar: &Condvar, state: MutexGuard<'a, State<T>>, timeout: Duration)
+                      -> MutexGuard<'a, State<T>> {
+    condvar.wait_timeout(state, timeout).unwrap_or_else(|e| e.into_inner()).0
+  }
+
+  /// Runs `f`, which may call into `Ord`, on the heap of the locked `state`. If
+  /// `f` panics, the queue is closed and emptied, and every blocked thread is
+  /// woken, before the lock is released.
+  fn with_heap<R, F: FnOnce(&mut WeakHeap<T>) -> R>(&self, state: &mut State<T>, f: F) -> R {
+    let mut guard = PoisonGuard { queue: self, state, done: false, };
+    let result = f(&mut guard.state.heap);
+    guard.done = true;
+    result
+  }
+
+  /// Returns `true` iff a panic while the queue was locked has poisoned it. A
+  /// poisoned queue is closed and empty.
+  pub fn is_poisoned(&self) -> bool {
+    self.state.is_poisoned()
+  }
+
+  fn is_full(&self, state: &State<T>) -> bool {
+    self.capacity.is_some_and(|capacity| state.heap.len() >= capacity)
+  }
+
+  /// Returns the maximum number of elements the queue holds, or `None` if it is
+  /// unbounded.
+  pub fn capacity(&self) -> Option<usize> {
+    self.capacity
+  }
+
+  /// Returns the number of elements in the queue.
+  pub fn len(&self) -> usize {
+    self.lock().heap.len()
+  }
+
+  /// Returns `true` iff the queue is empty.
+  pub fn is_empty(&self) -> bool {
+    self.lock().heap.is_empty()
+  }
+
+  /// Returns `true` iff the queue has been closed.
+  pub fn is_closed(&self) -> bool {
+    self.lock().closed
+  }
+
+  /// Pushes `value` onto the queue, blocking while it is full. Fails if the
+  /// queue is closed, including while blocked.
+  pub fn push(&self, value: T) -> Result<(), PushError<T>> {
+    let mut state = self.lock();
+    while !state.closed && self.is_full(&state) {
+      state = self.wait(&self.not_full, state);
+    }
+    if state.closed {
+      return Err(PushError(value));
+    }
+    self.with_heap(&mut state, |heap| heap.push(value));
+    drop(state);
+    self.not_empty.notify_one();
+    Ok(())
+  }
+
+  /// Pushes `value` onto the queue if it is neither full nor closed.
+  pub fn try_push(&self, value: T) -> Result<(), TryPushError<T>> {
+    let mut state = self.lock();
+    if state.closed {
+      return Err(TryPushError::Closed(value));
+    }
+    if self.is_full(&state) {
+      return Err(TryPushError::Full(value));
+    }
+    self.with_heap(&mut state, |heap| heap.push(value));
+    drop(state);
+    self.not_empty.notify_one();
+    Ok(())
+  }
+
+  /// Removes the greatest element from the queue and returns it, blocking while
+  /// the queue is empty. Returns `None` once the queue is empty and closed.
+  pub fn pop(&self) -> Option<T> {
+    let mut state = self.lock();
+    loop {
+      if let Some(value) = self.take(&mut state) {
+        return Some(value);
+      }
+      if state.closed {
+        return None;
+      }
+      state = self.wait(&self.not_empty, state);
+    }
+  }
+
+  /// Removes the greatest element from the queue and returns it, blocking for
+  /// at most `timeout` while the queue is empty.
+  pub fn pop_timeout(&self, timeout: Duration) -> Result<T, PopTimeoutError> {
+    let deadline = Instant::now().checked_add(timeout);
+    let mut state = self.lock();
+    loop {
+      if let Some(value) = self.take(&mut state) {
+        return Ok(value);
+      }
+      if state.closed {
+        return Err(PopTimeoutError::Closed);
+      }
+      let now = Instant::now();
+      state = match deadline {
+        Some(deadline) if deadline <= now => return Err(PopTimeoutError::Timeout),
+        Some(deadline) => self.wait_timeout(&self.not_empty, state, deadline - now),
+        // A timeout too large to represent never elapses.
+        None => self.wait(&self.not_empty, state),
+      };
+    }
+  }
+
+  /// Removes the greatest element from the queue and returns it, if there is
+  /// one, without blocking.
+  pub fn try_pop(&self) -> Result<T, TryPopError> {
+    let mut state = self.lock();
+    match self.take(&mut state) {
+      Some(value) => Ok(value),
+      None if state.closed => Err(TryPopError::Closed),
+      None => Err(TryPopError::Empty),
+    }
+  }
+
+  /// Pops from the locked `state`, waking a blocked pusher if there is room.
+  fn take(&self, state: &mut State<T>) -> Option<T> {
+    let value = self.with_heap(state, WeakHeap::pop);
+    if value.is_some() && self.capacity.is_some() {
+      self.not_full.notify_one();
+    }
+    value
+  }
+
+  /// Closes the queue, waking every blocked thread. Subsequent pushes fail, and
+  /// pops fail once the remaining elements have been removed. Closing a closed
+  /// queue does nothing.
+  pub fn close(&self) {
+    self.lock().closed = true;
+    self.not_empty.notify_all();
+    self.not_full.notify_all();
+  }
+
+  /// Closes the queue and returns the elements remaining in it, in
+  /// ascending order.
+  pub fn close_and_drain(&self) -> Vec<T> {
+    let mut state = self.lock();
+    state.closed = true;
+    let remaining = std::mem::take(&mut state.heap);
+    drop(state);
+    self.not_empty.notify_all();
+    self.not_full.notify_all();
+    remaining.into_sorted_vec()
+  }
+
+  /// Closes the queue and drops the elements remaining in it, unsorted and
+  /// after releasing the lock, so that no comparisons are made.
+  pub(crate) fn close_and_discard(&self) {
+    let mut state = self.lock();
+    state.closed = true;
+    let remaining = std::mem::take(&mut state.heap);
+    drop(state);
+    self.not_empty.notify_all();
+    self.not_full.notify_all();
+    drop(remaining);
+  }
+}
+
+/// Poisons the queue if dropped before `done` is set. See
+/// `SyncWeakHeap::with_heap`.
+struct PoisonGuard<'a, T: fmt::Debug + Ord> {
+  queue: &'a SyncWeakHeap<T>,
+  state: &'a mut State<T>,
+  done: bool,
+}
+
+impl<'a, T: fmt::Debug + Ord> Drop for PoisonGuard<'a, T> {
+  fn drop(&mut self) {
+    if !self.done {
+      self.state.closed = true;
+      self.state.heap = WeakHeap::new();
+      self.queue.not_empty.notify_all();
+      self.queue.not_full.notify_all();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Barrier};
+  use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+  use std::thread;
+  use std::time::Duration;
+  use super::{PopTimeoutError, PushError, SyncWeakHeap, TryPopError, TryPushError};
+
+  const PRODUCERS: usize = 8;
+  const CONSUMERS: usize = 8;
+  const PER_PRODUCER: usize = 5_000;
+
+  fn contention(queue: SyncWeakHeap<usize>) {
+    let queue = Arc::new(queue);
+    let start = Arc::new(Barrier::new(PRODUCERS + CONSUMERS));
+    let producers: Vec<_> = (0..PRODUCERS).map(|p| {
+      let queue = Arc::clone(&queue);
+      let start = Arc::clone(&start);
+      thread::spawn(move || {
+        start.wait();
+        for i in 0..PER_PRODUCER {
+          queue.push(p * PER_PRODUCER + i).unwrap();
+        }
+      })
+    }).collect();
+    let consumers: Vec<_> = (0..CONSUMERS).map(|c| {
+      let queue = Arc::clone(&queue);
+      let start = Arc::clone(&start);
+      thread::spawn(move || {
+        start.wait();
+        let mut popped = Vec::new();
+        loop {
+          // Mix the three ways of popping.
+          let value = match popped.len() % 3 {
+            0 => queue.pop(),
+            1 => match queue.try_pop() {
+              Ok(x) => Some(x),
+              Err(TryPopError::Empty) => {
+                thread::yield_now();
+                continue;
+              },
+              Err(TryPopError::Closed) => None,
+            },
+            _ => match queue.pop_timeout(Duration::from_millis(1 + c as u64)) {
+              Ok(x) => Some(x),
+              Err(PopTimeoutError::Timeout) => continue,
+              Err(PopTimeoutError::Closed) => None,
+            },
+          };
+          match value {
+            Some(x) => popped.push(x),
+            None => return popped,
+          }
+        }
+      })
+    }).collect();
+    for producer in producers {
+      producer.join().unwrap();
+    }
+    queue.close();
+    let mut popped: Vec<usize> = consumers.into_iter().flat_map(|c| c.join().unwrap()).collect();
+    popped.sort_unstable();
+    assert_eq!(popped, (0..PRODUCERS * PER_PRODUCER).collect::<Vec<_>>());
+    assert!(queue.is_empty());
+  }
+
+  #[test]
+  fn unbounded_contention() {
+    contention(SyncWeakHeap::new());
+  }
+
+  #[test]
+  fn bounded_contention() {
+    contention(SyncWeakHeap::bounded(16));
+  }
+
+  #[test]
+  fn bound_is_respected() {
+    let queue = Arc::new(SyncWeakHeap::bounded(3));
+    let high_water = Arc::new(AtomicUsize::new(0));
+    let producer = {
+      let queue = Arc::clone(&queue);
+      thread::spawn(move || {
+        for x in 0..1000 {
+          queue.push(x).unwrap();
+        }
+        queue.close();
+      })
+    };
+    let mut count = 0;
+    while queue.pop().is_some() {
+      high_water.fetch_max(queue.len(), Ordering::Relaxed);
+      count += 1;
+    }
+    producer.join().unwrap();
+    assert_eq!(count, 1000);
+    assert!(high_water.load(Ordering::Relaxed) <= 3);
+  }
+
+  #[test]
+  fn push_blocks_at_capacity() {
+    let queue = Arc::new(SyncWeakHeap::bounded(2));
+    queue.push(1).unwrap();
+    queue.push(2).unwrap();
+    let pushed = Arc::new(AtomicBool::new(false));
+    let pusher = {
+      let queue = Arc::clone(&queue);
+      let pushed = Arc::clone(&pushed);
+      thread::spawn(move || {
+        queue.push(3).unwrap();
+        pushed.store(true, Ordering::SeqCst);
+      })
+    };
+    thread::sleep(Duration::from_millis(50));
+    assert!(!pushed.load(Ordering::SeqCst));
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.pop(), Some(2));
+    pusher.join().unwrap();
+    assert!(pushed.load(Ordering::SeqCst));
+    assert_eq!(queue.close_and_drain(), vec![1, 3]);
+  }
+
+  #[test]
+  fn bounded_does_not_preallocate() {
+    let queue = SyncWeakHeap::bounded(usize::MAX);
+    queue.push(1).unwrap();
+    assert_eq!(queue.pop(), Some(1));
+  }
+
+  /// An element whose comparisons panic once `EXPLODE` is set.
+  #[derive(Debug, Eq, PartialEq)]
+  struct Fragile(i32);
+
+  static EXPLODE: AtomicBool = AtomicBool::new(false);
+
+  impl PartialOrd for Fragile {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+      Some(self.cmp(other))
+    }
+  }
+
+  impl Ord for Fragile {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+      assert!(!EXPLODE.load(Ordering::SeqCst), "comparison exploded");
+      self.0.cmp(&other.0)
+    }
+  }
+
+  #[test]
+  fn panic_in_ord_closes_the_queue_and_wakes_blocked_threads() {
+    let queue = Arc::new(SyncWeakHeap::bounded(3));
+    for x in 0..3 {
+      queue.push(Fragile(x)).unwrap();
+    }
+    let ready = Arc::new(Barrier::new(2));
+    let pusher = {
+      let (queue, ready) = (Arc::clone(&queue), Arc::clone(&ready));
+      thread::spawn(move || {
+        ready.wait();
+        // Blocks while the queue is full, unless it has already been closed.
+        queue.push(Fragile(3)).map_err(|e| e.0)
+      })
+    };
+    ready.wait();
+    EXPLODE.store(true, Ordering::SeqCst);
+    // Popping sifts the last element down from the root, which compares it.
+    let popped = thread::spawn({
+      let queue = Arc::clone(&queue);
+      move || queue.try_pop()
+    }).join();
+    EXPLODE.store(false, Ordering::SeqCst);
+    assert!(popped.is_err());
+    assert_eq!(pusher.join().unwrap(), Err(Fragile(3)));
+    assert!(queue.is_poisoned());
+    assert!(queue.is_closed());
+    assert!(queue.is_empty());
+    assert_eq!(queue.try_pop(), Err(TryPopError::Closed));
+  }
+
+  #[test]
+  fn pops_in_order() {
+    let queue = SyncWeakHeap::new();
+    for x in &[3, 1, 4, 1, 5, 9, 2, 6] {
+      queue.push(*x).unwrap();
+    }
+    let popped: Vec<i32> = (0..8).map(|_| queue.try_pop().unwrap()).collect();
+    assert_eq!(popped, vec![9, 6, 5, 4, 3, 2, 1, 1]);
+    assert_eq!(queue.try_pop(), Err(TryPopError::Empty));
+    assert_eq!(queue.pop_timeout(Duration::from_millis(10)), Err(PopTimeoutError::Timeout));
+  }
+
+  #[test]
+  fn try_push_reports_full_and_closed() {
+    let queue = SyncWeakHeap::bounded(1);
+    assert!(queue.try_push(1).is_ok());
+    assert_eq!(queue.try_push(2).map_err(TryPushError::into_inner), Err(2));
+    assert!(matches!(queue.try_push(2), Err(TryPushError::Full(2))));
+    queue.close();
+    assert!(matches!(queue.try_push(3), Err(TryPushError::Closed(3))));
+    assert_eq!(queue.pop(), Some(1));
+    assert_eq!(queue.pop(), None);
+    assert_eq!(queue.try_pop(), Err(TryPopError::Closed));
+  }
+
+  #[test]
+  fn close_wakes_blocked_threads() {
+    let queue = Arc::new(SyncWeakHeap::bounded(1));
+    queue.push(0).unwrap();
+    let pusher = {
+      let queue = Arc::clone(&queue);
+      thread::spawn(move || queue.push(1))
+    };
+    let empty = Arc::new(SyncWeakHeap::<i32>::new());
+    let popper = {
+      let empty = Arc::clone(&empty);
+      thread::spawn(move || empty.pop())
+    };
+    thread::sleep(Duration::from_millis(50));
+    queue.close();
+    empty.close();
+    assert_eq!(pusher.join().unwrap(), Err(PushError(1)));
+    assert_eq!(popper.join().unwrap(), None);
+    assert_eq!(queue.close_and_drain(), vec![0]);
+  }
+}
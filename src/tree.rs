@@ -0,0 +1,251 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A read-only view of the structure of a `WeakHeap`, as returned by
+//! `WeakHeap::tree`.
+//!
+//! The heap can be navigated either as a binary tree, through
+//! `Node::left_child` and `Node::right_child`, or as the equivalent
+//! heap-ordered multi-way tree, through `Node::children` and
+//! `Node::distinguished_ancestor`. See `render::View` for a description of the
+//! two.
+//!
+//! ```rust
+//! # use weak_heap::WeakHeap;
+//! # use weak_heap::tree::Node;
+//! # fn main() {
+//! let heap: WeakHeap<i32> = (0..100).collect();
+//! fn height(node: Node<i32>) -> usize {
+//!   1 + node.children().map(height).max().unwrap_or(0)
+//! }
+//! let root = heap.tree().root().unwrap();
+//! assert_eq!(*root.value(), 99);
+//! assert_eq!(height(root), 8);
+//! # }
+//! ```
+
+use std::fmt;
+use crate::{Observer, WeakHeap};
+
+/// A read-only view of the structure of a `WeakHeap`.
+pub struct Tree<'a, T: fmt::Debug + Ord, O: Observer = ()> {
+  heap: &'a WeakHeap<T, O>,
+}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> Tree<'a, T, O> {
+  /// Returns the root of the heap, or `None` if the heap is empty.
+  pub fn root(&self) -> Option<Node<'a, T, O>> {
+    self.node(0)
+  }
+
+  /// Returns the node stored at `offset`, or `None` if `offset` is out of
+  /// bounds.
+  pub fn node(&self, offset: usize) -> Option<Node<'a, T, O>> {
+    if offset < self.heap.len() {
+      Some(Node { heap: self.heap, offset, })
+    } else {
+      None
+    }
+  }
+
+  /// Returns every node in the heap, in storage order.
+  pub fn nodes(&self) -> impl Iterator<Item = Node<'a, T, O>> + 'a {
+    let heap = self.heap;
+    (0..heap.len()).map(move |offset| Node { heap, offset, })
+  }
+
+  /// Returns the number of nodes in the heap.
+  pub fn len(&self) -> usize {
+    self.heap.len()
+  }
+
+  /// Returns `true` iff the heap is empty.
+  pub fn is_empty(&self) -> bool {
+    self.heap.is_empty()
+  }
+}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> Clone for Tree<'a, T, O> {
+  fn clone(&self) -> Self {
+    *self
+  }
+}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> Copy for Tree<'a, T, O> {}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> fmt::Debug for Tree<'a, T, O> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_list().entries(self.nodes()).finish()
+  }
+}
+
+/// A handle to a single element of a `WeakHeap`.
+pub struct Node<'a, T: fmt::Debug + Ord, O: Observer = ()> {
+  heap: &'a WeakHeap<T, O>,
+  offset: usize,
+}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> Node<'a, T, O> {
+  /// Returns the offset at which this element is stored. The root is at
+  /// offset 0, and the children of the element at offset `i > 0` in the
+  /// binary tree are at offsets `2i` and `2i + 1`.
+  pub fn offset(&self) -> usize {
+    self.offset
+  }
+
+  /// Returns this element's value.
+  pub fn value(&self) -> &'a T {
+    &self.heap.data[self.offset].value
+  }
+
+  /// Returns this element's valence bit. If it is `true`, then the element's
+  /// left child is at offset `2i + 1` and its right child at `2i`; otherwise
+  /// the reverse. The valence of the root has no meaning.
+  pub fn valence(&self) -> bool {
+    self.heap.data[self.offset].valence
+  }
+
+  /// Returns the number of edges between this element and the root in the
+  /// binary tree.
+  pub fn depth(&self) -> usize {
+    match self.offset {
+      0 => 0,
+      n => (usize::BITS - n.leading_zeros()) as usize,
+    }
+  }
+
+  /// Returns this element's parent in the binary tree, or `None` if it is the
+  /// root.
+  pub fn parent(&self) -> Option<Node<'a, T, O>> {
+    if self.offset == 0 {
+      None
+    } else {
+      Some(self.at(self.offset / 2))
+    }
+  }
+
+  /// Returns this element's left child in the binary tree. The left child
+  /// shares this element's distinguished ancestor.
+  pub fn left_child(&self) -> Option<Node<'a, T, O>> {
+    self.heap.left_child_offset(self.offset).map(|offset| self.at(offset))
+  }
+
+  /// Returns this element's right child in the binary tree. This element is
+  /// the right child's distinguished ancestor.
+  pub fn right_child(&self) -> Option<Node<'a, T, O>> {
+    self.heap.right_child_offset(self.offset).map(|offset| self.at(offset))
+  }
+
+  /// Returns this element's children in the multi-way tree: the elements of
+  /// which it is the distinguished ancestor. They are returned starting with
+  /// its right child and then following left children from there, so the
+  /// subtree of each child is smaller than that of the one before it.
+  pub fn children(&self) -> impl Iterator<Item = Node<'a, T, O>> + 'a {
+    let heap = self.heap;
+    heap.children_offsets(self.offset).map(move |offset| Node { heap, offset, })
+  }
+
+  /// Returns this element's distinguished ancestor, its parent in the
+  /// multi-way tree, or `None` if it is the root. The weak heap ordering
+  /// requires that no element is greater than its distinguished ancestor.
+  pub fn distinguished_ancestor(&self) -> Option<Node<'a, T, O>> {
+    if self.offset == 0 {
+      None
+    } else {
+      Some(self.at(self.heap.distinguished_ancestor_offset(self.offset)))
+    }
+  }
+
+  fn at(&self, offset: usize) -> Node<'a, T, O> {
+    Node { heap: self.heap, offset, }
+  }
+}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> Clone for Node<'a, T, O> {
+  fn clone(&self) -> Self {
+    *self
+  }
+}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> Copy for Node<'a, T, O> {}
+
+impl<'a, T: fmt::Debug + Ord, O: Observer> fmt::Debug for Node<'a, T, O> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Node")
+      .field("offset", &self.offset)
+      .field("value", self.value())
+      .field("valence", &self.valence())
+      .finish()
+  }
+}
+
+impl<T: fmt::Debug + Ord, O: Observer> WeakHeap<T, O> {
+  /// Returns a read-only view of the structure of the heap.
+  pub fn tree(&self) -> Tree<'_, T, O> {
+    Tree { heap: self, }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::WeakHeap;
+
+  #[test]
+  fn empty() {
+    let heap = WeakHeap::<i32>::new();
+    assert!(heap.tree().root().is_none());
+    assert!(heap.tree().is_empty());
+    assert_eq!(heap.tree().nodes().count(), 0);
+  }
+
+  #[test]
+  fn binary_and_multi_way_views_agree() {
+    let mut heap: WeakHeap<i32> = (0..50).map(|x| (x * 37) % 50).collect();
+    for x in 0..20 {
+      heap.pop();
+      heap.push(x * 3);
+    }
+    let tree = heap.tree();
+    let mut seen = vec![false; tree.len()];
+    seen[0] = true;
+    for node in tree.nodes() {
+      if let Some(right) = node.right_child() {
+        assert_eq!(right.distinguished_ancestor().unwrap().offset(), node.offset());
+        assert_eq!(right.parent().unwrap().offset(), node.offset());
+      }
+      if let Some(left) = node.left_child() {
+        assert_eq!(left.distinguished_ancestor().map(|n| n.offset()),
+                   node.distinguished_ancestor().map(|n| n.offset()));
+        assert_eq!(left.depth(), node.depth() + 1);
+      }
+      for child in node.children() {
+        assert_eq!(child.distinguished_ancestor().unwrap().offset(), node.offset());
+        assert!(child.value() <= node.value());
+        assert!(!seen[child.offset()]);
+        seen[child.offset()] = true;
+      }
+    }
+    assert!(seen.iter().all(|x| *x));
+  }
+
+  #[test]
+  fn root_has_single_right_child() {
+    let heap: WeakHeap<i32> = (0..10).collect();
+    let root = heap.tree().root().unwrap();
+    assert!(root.left_child().is_none());
+    assert_eq!(root.right_child().unwrap().offset(), 1);
+    assert!(root.distinguished_ancestor().is_none());
+    assert_eq!(root.depth(), 0);
+  }
+}